@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+
+use super::config::Config;
+use super::db::{self, InstalledPackage};
+use super::transaction::{self, Transaction};
+use super::upgrade::format_size;
+use super::version::Version;
+
+/// A package archive sitting in the cache.
+#[derive(Debug)]
+struct CachedPackage {
+    path: PathBuf,
+    name: String,
+    version: Version,
+}
+
+pub fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load(cli)?;
+    let (root, dbpath) = db::paths(cli);
+    let keep = match cli.value_of("keep") {
+        Some(keep) => match keep.parse::<usize>() {
+            Ok(keep) => Some(keep),
+            Err(_) => return Err(format!("invalid number of versions to keep: {}", keep))?,
+        },
+        None => None,
+    };
+
+    // upgrades download without holding the database lock so the cache has
+    // a lock of its own, which stops us from removing a download that's
+    // still being written or a package that's about to be installed
+    let cache = config.cache_dir(cli, &root);
+    let _cache_lock = transaction::lock_cache(&cache)?;
+
+    // and the database lock keeps what's installed from changing under us
+    let txn = Transaction::begin(&root, &dbpath)?;
+    let installed = txn.localdb().packages()?;
+
+    let (packages, mut remove) = read_cache(&cache)?;
+    remove.extend(select(&packages, &installed, keep));
+    remove.sort();
+
+    let mut freed = 0;
+    for path in remove.iter() {
+        freed += fs::metadata(path)?.len();
+        fs::remove_file(path)?;
+        println!("removed {}", path.display());
+    }
+
+    txn.commit()?;
+
+    println!(
+        "{} files removed, {} freed",
+        remove.len(),
+        format_size(freed)
+    );
+
+    Ok(())
+}
+
+/// Finds the package archives in the cache, along with any downloads that
+/// were interrupted and can always be removed.
+fn read_cache(
+    cache: &Path,
+) -> Result<(Vec<CachedPackage>, Vec<PathBuf>), Box<dyn std::error::Error>> {
+    let mut packages = Vec::new();
+    let mut partial = Vec::new();
+
+    if !cache.exists() {
+        return Ok((packages, partial));
+    }
+
+    for entry in fs::read_dir(cache)? {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        let filename = path.file_name().unwrap().to_str().unwrap_or_default();
+        if filename.ends_with(".part") || filename.ends_with(".part.validators") {
+            partial.push(path);
+        } else if let Some((name, version)) = parse_filename(filename) {
+            packages.push(CachedPackage {
+                path,
+                name,
+                version,
+            });
+        }
+    }
+
+    Ok((packages, partial))
+}
+
+/// Splits a package filename (`name-version-release-arch.pkg.tar.*`) into the
+/// package name and its version, or `None` if it isn't a package.
+fn parse_filename(filename: &str) -> Option<(String, Version)> {
+    let (basename, extension) = filename.split_once(".pkg.tar.")?;
+    if extension.contains('.') {
+        return None;
+    }
+
+    // names can have dashes in them but nothing else can
+    let mut parts = basename.rsplitn(4, '-');
+    let _arch = parts.next()?;
+    let release = parts.next()?;
+    let version = parts.next()?;
+    let name = parts.next()?;
+
+    match format!("{}-{}", version, release).parse() {
+        Ok(version) => Some((name.to_string(), version)),
+        Err(_) => None,
+    }
+}
+
+/// The cached packages to remove: either everything but the newest `keep`
+/// versions of each package, or everything that isn't currently installed.
+fn select(
+    packages: &[CachedPackage],
+    installed: &[InstalledPackage],
+    keep: Option<usize>,
+) -> Vec<PathBuf> {
+    match keep {
+        Some(keep) => {
+            let mut by_name: BTreeMap<&str, Vec<&CachedPackage>> = BTreeMap::new();
+            for package in packages.iter() {
+                by_name.entry(&package.name).or_default().push(package);
+            }
+
+            by_name
+                .into_values()
+                .flat_map(|mut versions| {
+                    versions.sort_by(|a, b| b.version.cmp(&a.version));
+                    versions.into_iter().skip(keep)
+                })
+                .map(|p| p.path.clone())
+                .collect()
+        }
+        None => packages
+            .iter()
+            .filter(|p| {
+                !installed
+                    .iter()
+                    .any(|i| i.name == p.name && i.pkgver() == p.version)
+            })
+            .map(|p| p.path.clone())
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cache() -> Vec<CachedPackage> {
+        [
+            "foo-1.0-1-any.pkg.tar.gz",
+            "foo-1.10-1-any.pkg.tar.zst",
+            "foo-1.2-1-any.pkg.tar.gz",
+            "lib-foo-bar-2.0-3-x86_64.pkg.tar.xz",
+            "baz-1:0.1-1-any.pkg.tar.gz",
+        ]
+        .iter()
+        .map(|filename| {
+            let (name, version) = parse_filename(filename).unwrap();
+            CachedPackage {
+                path: PathBuf::from(filename),
+                name,
+                version,
+            }
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_parse_filename() {
+        let (name, version) = parse_filename("lib-foo-bar-2.0-3-x86_64.pkg.tar.xz").unwrap();
+        assert_eq!(name, "lib-foo-bar");
+        assert_eq!(version, "2.0-3".parse().unwrap());
+
+        let (name, version) = parse_filename("baz-1:0.1-1-any.pkg.tar.gz").unwrap();
+        assert_eq!(name, "baz");
+        assert_eq!(version, "1:0.1-1".parse().unwrap());
+
+        assert!(parse_filename("core.db").is_none());
+        assert!(parse_filename("foo-any.pkg.tar.gz").is_none());
+        assert!(parse_filename("foo-1.0-1-any.pkg.tar.gz.sig").is_none());
+    }
+
+    #[test]
+    fn test_select() {
+        let packages = test_cache();
+        let installed = vec![
+            InstalledPackage::for_test("foo", "1.2"),
+            InstalledPackage::for_test("other", "1.0"),
+        ];
+
+        let mut removed = select(&packages, &installed, None);
+        removed.sort();
+        assert_eq!(
+            removed,
+            vec![
+                PathBuf::from("baz-1:0.1-1-any.pkg.tar.gz"),
+                PathBuf::from("foo-1.0-1-any.pkg.tar.gz"),
+                PathBuf::from("foo-1.10-1-any.pkg.tar.zst"),
+                PathBuf::from("lib-foo-bar-2.0-3-x86_64.pkg.tar.xz"),
+            ]
+        );
+
+        let removed = select(&packages, &installed, Some(2));
+        assert_eq!(removed, vec![PathBuf::from("foo-1.0-1-any.pkg.tar.gz")]);
+
+        let mut removed = select(&packages, &installed, Some(0));
+        removed.sort();
+        assert_eq!(removed.len(), packages.len());
+    }
+
+    #[test]
+    fn test_read_cache() {
+        let cache = std::env::temp_dir().join(format!("mpm-clean-{}", std::process::id()));
+        fs::create_dir_all(cache.join("subdir")).unwrap();
+        for file in [
+            "foo-1.0-1-any.pkg.tar.gz",
+            "foo-1.1-1-any.pkg.tar.gz.part",
+            "foo-1.1-1-any.pkg.tar.gz.part.validators",
+            "notes.txt",
+        ] {
+            fs::write(cache.join(file), file).unwrap();
+        }
+
+        let (packages, mut partial) = read_cache(&cache).unwrap();
+        assert_eq!(packages.len(), 1);
+        assert_eq!(packages[0].name, "foo");
+        partial.sort();
+        assert_eq!(
+            partial,
+            vec![
+                cache.join("foo-1.1-1-any.pkg.tar.gz.part"),
+                cache.join("foo-1.1-1-any.pkg.tar.gz.part.validators"),
+            ]
+        );
+
+        assert!(read_cache(&cache.join("missing")).unwrap().0.is_empty());
+
+        fs::remove_dir_all(&cache).unwrap();
+    }
+}
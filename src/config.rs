@@ -0,0 +1,279 @@
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::ArgMatches;
+use reqwest::Client;
+use serde::Deserialize;
+
+use super::downloader::Retry;
+use super::sync::Repository;
+
+pub static CONFIG_FILE: &str = "/etc/mpm.conf";
+static CONFIG_ENV: &str = "MPM_CONFIG";
+/// Where repositories were configured before they moved into the config file.
+static LEGACY_REPOS_FILE: &str = "/etc/mpm/repos.yaml";
+static CACHEDIR: &str = "var/cache/mpm/pkg";
+static SOURCEDIR: &str = "mpm/sources";
+
+/// How built packages are compressed, which also decides their extension.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// The bsdtar flag that selects this compression.
+    pub fn flag(&self) -> &str {
+        match self {
+            Compression::Gzip => "-z",
+            Compression::Xz => "-J",
+            Compression::Zstd => "--zstd",
+        }
+    }
+
+    pub fn extension(&self) -> &str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Xz => "xz",
+            Compression::Zstd => "zst",
+        }
+    }
+}
+
+/// Settings shared by every command, read from `/etc/mpm.conf` (or wherever
+/// `MPM_CONFIG` or `--config` point). Anything that isn't set falls back to
+/// its default, and command line flags take precedence over all of it.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Repositories in the order that they're searched for packages. Unset
+    /// is different from empty, as only unset falls back to the legacy list.
+    pub repos: Option<Vec<Repository>>,
+    /// Where downloaded packages are kept, inside of the root if unset.
+    pub cache_dir: Option<PathBuf>,
+    /// Where packages are built, the current directory if unset.
+    pub build_dir: PathBuf,
+    /// Where downloaded recipe sources are kept between builds.
+    pub source_dir: Option<PathBuf>,
+    /// Who to credit in the metadata of packages that we build.
+    pub packager: Option<String>,
+    pub compression: Compression,
+    /// How many files to download at the same time.
+    pub parallel_downloads: usize,
+    /// How many times to retry a download that failed with a network or
+    /// server error.
+    pub retries: u32,
+    /// Milliseconds to wait before the first retry, doubling for each retry
+    /// after that.
+    pub retry_delay: u64,
+    /// Packages that are never upgraded.
+    pub ignore: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            repos: None,
+            cache_dir: None,
+            build_dir: PathBuf::from("."),
+            source_dir: None,
+            packager: None,
+            compression: Compression::Gzip,
+            parallel_downloads: 5,
+            retries: 3,
+            retry_delay: 1000,
+            ignore: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file named by `--config`, then `MPM_CONFIG`, then the
+    /// default location, which is the only one that's allowed to be missing.
+    pub fn load(cli: &ArgMatches) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = match cli.value_of("config") {
+            Some(path) => Self::from_file(Path::new(path))?,
+            None => match env::var_os(CONFIG_ENV) {
+                Some(path) => Self::from_file(Path::new(&path))?,
+                None if !Path::new(CONFIG_FILE).exists() => Config::default(),
+                None => Self::from_file(Path::new(CONFIG_FILE))?,
+            },
+        };
+
+        config.load_legacy_repos(Path::new(LEGACY_REPOS_FILE))?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("unable to read {}: {}", path.display(), err))?,
+        };
+
+        let config: Config = match serde_yaml::from_reader(file) {
+            Ok(config) => config,
+            Err(err) => return Err(format!("invalid config {}: {}", path.display(), err))?,
+        };
+
+        if config.parallel_downloads == 0 {
+            return Err(format!(
+                "invalid config {}: parallel_downloads must be at least 1",
+                path.display()
+            )
+            .into());
+        }
+
+        Ok(config)
+    }
+
+    /// The configured repositories, in the order that they're searched.
+    pub fn repos(&self) -> &[Repository] {
+        self.repos.as_deref().unwrap_or_default()
+    }
+
+    /// Falls back to the repositories in the old `repos.yaml` if there aren't
+    /// any in the config, so that upgrading mpm doesn't break syncing.
+    fn load_legacy_repos(&mut self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if self.repos.is_some() || !path.exists() {
+            return Ok(());
+        }
+
+        eprintln!(
+            "warning: {} is deprecated, move its repositories into the repos section of {}",
+            path.display(),
+            CONFIG_FILE
+        );
+        self.repos = Some(Repository::load_all(path)?);
+
+        Ok(())
+    }
+
+    /// Where downloaded packages for the system in `root` are kept, which can
+    /// be overridden with `--cachedir`.
+    pub fn cache_dir(&self, cli: &ArgMatches, root: &Path) -> PathBuf {
+        match (cli.value_of("cachedir"), &self.cache_dir) {
+            (Some(dir), _) => PathBuf::from(dir),
+            (None, Some(dir)) => dir.clone(),
+            (None, None) => root.join(CACHEDIR),
+        }
+    }
+
+    /// Where downloaded recipe sources are kept, which can be overridden with
+    /// `--sourcedir`. Unless it's configured this is the user's cache so that
+    /// builds don't need to be run as root.
+    pub fn source_dir(&self, cli: &ArgMatches) -> PathBuf {
+        if let Some(dir) = cli.value_of("sourcedir") {
+            return PathBuf::from(dir);
+        }
+        if let Some(dir) = &self.source_dir {
+            return dir.clone();
+        }
+
+        match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+            (Some(cache), _) => PathBuf::from(cache).join(SOURCEDIR),
+            (None, Some(home)) => PathBuf::from(home).join(".cache").join(SOURCEDIR),
+            (None, None) => self.build_dir.join("sources"),
+        }
+    }
+
+    /// Packages to leave alone when upgrading, both from the config and from
+    /// `--ignore`.
+    pub fn ignored(&self, cli: &ArgMatches) -> Vec<String> {
+        let mut ignored = self.ignore.clone();
+        if let Some(names) = cli.values_of("ignore") {
+            ignored.extend(names.map(|n| n.to_string()));
+        }
+
+        ignored
+    }
+
+    pub fn retry(&self) -> Retry {
+        Retry {
+            retries: self.retries,
+            delay: Duration::from_millis(self.retry_delay),
+        }
+    }
+
+    /// The HTTP client that every download goes through, keeping enough
+    /// connections around for the downloads that we run at the same time.
+    pub fn client(&self) -> Result<Client, Box<dyn std::error::Error>> {
+        let client = Client::builder()
+            .user_agent(concat!("mpm/", env!("CARGO_PKG_VERSION")))
+            .pool_max_idle_per_host(self.parallel_downloads)
+            .build()?;
+
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_from_file() {
+        let dir = std::env::temp_dir().join(format!("mpm-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("mpm.conf");
+        fs::write(
+            &path,
+            "repos:\n  - name: core\n    mirrors:\n      - https://example.com/core\n\
+             cache_dir: /srv/cache\ncompression: zstd\nignore:\n  - linux\n",
+        )
+        .unwrap();
+
+        let config = Config::from_file(&path).unwrap();
+        assert_eq!(config.repos().len(), 1);
+        assert_eq!(config.repos()[0].mirrors, vec!["https://example.com/core"]);
+        assert_eq!(config.cache_dir, Some(PathBuf::from("/srv/cache")));
+        assert_eq!(config.compression, Compression::Zstd);
+        assert_eq!(config.ignore, vec!["linux"]);
+
+        // everything else keeps its default
+        assert_eq!(config.build_dir, PathBuf::from("."));
+        assert_eq!(config.parallel_downloads, 5);
+        assert_eq!(
+            config.retry(),
+            Retry {
+                retries: 3,
+                delay: Duration::from_secs(1),
+            }
+        );
+
+        // repositories from the old list are only used if the config doesn't
+        // have a list of its own, even an empty one
+        let legacy = dir.join("repos.yaml");
+        fs::write(
+            &legacy,
+            "- name: old\n  mirrors:\n    - https://example.com/old\n",
+        )
+        .unwrap();
+        let mut config = Config::from_file(&path).unwrap();
+        config.load_legacy_repos(&legacy).unwrap();
+        assert_eq!(config.repos()[0].name, "core");
+        fs::write(&path, "repos: []\n").unwrap();
+        let mut config = Config::from_file(&path).unwrap();
+        config.load_legacy_repos(&legacy).unwrap();
+        assert!(config.repos().is_empty());
+        let mut config = Config::default();
+        config.load_legacy_repos(&legacy).unwrap();
+        assert_eq!(config.repos()[0].name, "old");
+        config.load_legacy_repos(&dir.join("missing.yaml")).unwrap();
+
+        fs::write(&path, "parallel_downloads: 0\n").unwrap();
+        assert!(Config::from_file(&path).is_err());
+        fs::write(&path, "cachedir: /srv/cache\n").unwrap();
+        assert!(Config::from_file(&path).is_err());
+        assert!(Config::from_file(&dir.join("missing.conf")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,474 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ArgMatches;
+use serde::{Deserialize, Serialize};
+
+use super::package::mtree::Mtree;
+use super::package::scriptlet::Scriptlets;
+use super::version::Version;
+
+static ROOT: &str = "/";
+static DBPATH: &str = "var/lib/mpm";
+static LOCALDB_DIR: &str = "local";
+static DESC_FILE: &str = "desc";
+static MTREE_FILE: &str = "mtree";
+static INSTALL_FILE: &str = "install";
+
+/// The database of packages installed on the system, stored as one directory
+/// per package under `dbpath/local`.
+pub struct LocalDatabase {
+    path: PathBuf,
+}
+
+/// Why a package was installed, packages that were only pulled in for
+/// something else are orphans once nothing depends on them any more.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InstallReason {
+    #[default]
+    Explicit,
+    Dependency,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub epoch: Option<u32>,
+    pub version: String,
+    pub release: u32,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub arch: String,
+    #[serde(default)]
+    pub builddate: u64,
+    #[serde(default)]
+    pub size: u64,
+    #[serde(default)]
+    pub license: Vec<String>,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    #[serde(default)]
+    pub provides: Vec<String>,
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub replaces: Vec<String>,
+    pub install_date: u64,
+    #[serde(default)]
+    pub reason: InstallReason,
+    #[serde(default)]
+    pub files: Vec<String>,
+    /// The files that an admin may have changed, along with the checksum of
+    /// each as the package shipped it.
+    #[serde(default)]
+    pub backup: BTreeMap<String, String>,
+}
+
+#[cfg(test)]
+impl InstalledPackage {
+    /// An explicitly installed package with nothing else set, for tests to
+    /// fill in whatever they need with struct update syntax.
+    pub fn for_test(name: &str, version: &str) -> Self {
+        InstalledPackage {
+            name: String::from(name),
+            epoch: None,
+            version: String::from(version),
+            release: 1,
+            description: None,
+            url: None,
+            arch: String::from("any"),
+            builddate: 0,
+            size: 0,
+            license: Vec::new(),
+            depends: Vec::new(),
+            provides: Vec::new(),
+            conflicts: Vec::new(),
+            replaces: Vec::new(),
+            install_date: 0,
+            reason: InstallReason::Explicit,
+            files: Vec::new(),
+            backup: BTreeMap::new(),
+        }
+    }
+}
+
+impl InstalledPackage {
+    pub fn full_version(&self) -> String {
+        if let Some(epoch) = self.epoch {
+            format!("{}:{}-{}", epoch, self.version, self.release)
+        } else {
+            format!("{}-{}", self.version, self.release)
+        }
+    }
+
+    pub fn pkgver(&self) -> Version {
+        Version::new(self.epoch, &self.version, self.release)
+    }
+}
+
+impl LocalDatabase {
+    /// Opens the database without creating anything, so that looking at a
+    /// root that nothing has been installed into yet doesn't need to be able
+    /// to write to it.
+    pub fn open(dbpath: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(LocalDatabase {
+            path: dbpath.join(LOCALDB_DIR),
+        })
+    }
+
+    /// Opens the database, creating it if it doesn't exist yet.
+    pub fn create(dbpath: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let localdb = Self::open(dbpath)?;
+        fs::create_dir_all(&localdb.path)?;
+        Ok(localdb)
+    }
+
+    pub fn packages(&self) -> Result<Vec<InstalledPackage>, Box<dyn std::error::Error>> {
+        let mut packages = Vec::new();
+        if !self.path.exists() {
+            return Ok(packages);
+        }
+
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().unwrap();
+
+            // anything hidden is an in-progress (or interrupted) write
+            if name.starts_with('.') {
+                continue;
+            }
+
+            if let Some(package) = self.get(&name)? {
+                packages.push(package);
+            }
+        }
+
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(packages)
+    }
+
+    pub fn get(&self, name: &str) -> Result<Option<InstalledPackage>, Box<dyn std::error::Error>> {
+        let desc = self.path.join(name).join(DESC_FILE);
+        if !desc.exists() {
+            return Ok(None);
+        }
+
+        let file = File::open(&desc)?;
+        match serde_yaml::from_reader(file) {
+            Ok(package) => Ok(Some(package)),
+            Err(err) => Err(format!(
+                "corrupt database entry {}: {}",
+                desc.display(),
+                err
+            ))?,
+        }
+    }
+
+    /// Adds the package to the database, replacing any existing entry with
+    /// the same name.
+    pub fn add(&self, package: &InstalledPackage) -> Result<(), Box<dyn std::error::Error>> {
+        let pkgdir = self.path.join(&package.name);
+        if !pkgdir.exists() {
+            fs::create_dir_all(&self.path)?;
+            fs::create_dir(&pkgdir)?;
+            sync_dir(&self.path)?;
+        }
+
+        write_atomic(&pkgdir.join(DESC_FILE), &serde_yaml::to_string(package)?)
+    }
+
+    /// Stores the file manifest that the package was shipped with so that its
+    /// installed files can be checked against it later.
+    pub fn set_mtree(&self, name: &str, mtree: &Mtree) -> Result<(), Box<dyn std::error::Error>> {
+        let pkgdir = self.path.join(name);
+        if !pkgdir.exists() {
+            return Err(format!("{} is not installed", name))?;
+        }
+
+        write_atomic(&pkgdir.join(MTREE_FILE), &mtree.to_string())
+    }
+
+    pub fn mtree(&self, name: &str) -> Result<Option<Mtree>, Box<dyn std::error::Error>> {
+        let path = self.path.join(name).join(MTREE_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        Ok(Some(Mtree::parse(&fs::read_to_string(path)?)?))
+    }
+
+    /// Stores the package's scriptlets so that its remove hooks can be run
+    /// later, or drops any stored ones if the package doesn't have any.
+    pub fn set_scriptlets(
+        &self,
+        name: &str,
+        scriptlets: Option<&Scriptlets>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pkgdir = self.path.join(name);
+        if !pkgdir.exists() {
+            return Err(format!("{} is not installed", name))?;
+        }
+
+        let path = pkgdir.join(INSTALL_FILE);
+        match scriptlets {
+            Some(scriptlets) => write_atomic(&path, &scriptlets.to_string()),
+            None if path.exists() => {
+                fs::remove_file(&path)?;
+                sync_dir(&pkgdir)
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn scriptlets(&self, name: &str) -> Result<Option<Scriptlets>, Box<dyn std::error::Error>> {
+        let path = self.path.join(name).join(INSTALL_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        Ok(Some(Scriptlets::parse(&fs::read_to_string(path)?)?))
+    }
+
+    pub fn remove(&self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let pkgdir = self.path.join(name);
+        if !pkgdir.exists() {
+            return Ok(());
+        }
+
+        // move the entry out of the way first so that if we crash while
+        // deleting it we don't leave a half-removed package behind
+        let tmpdir = self.path.join(format!(".{}.removing", name));
+        if tmpdir.exists() {
+            fs::remove_dir_all(&tmpdir)?;
+        }
+        fs::rename(&pkgdir, &tmpdir)?;
+        sync_dir(&self.path)?;
+
+        fs::remove_dir_all(&tmpdir)?;
+        Ok(())
+    }
+
+    /// Copies the package's entry to `dest` so that it can be put back later
+    /// with `restore`, returning `false` if the package isn't installed.
+    pub fn backup(&self, name: &str, dest: &Path) -> Result<bool, Box<dyn std::error::Error>> {
+        let pkgdir = self.path.join(name);
+        if !pkgdir.exists() {
+            return Ok(false);
+        }
+
+        fs::create_dir_all(dest)?;
+        for entry in fs::read_dir(&pkgdir)? {
+            let entry = entry?;
+            if !entry.file_name().to_str().unwrap().starts_with('.') {
+                fs::copy(entry.path(), dest.join(entry.file_name()))?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Replaces the package's entry with one saved by `backup`, or removes it
+    /// entirely if there wasn't one to save.
+    pub fn restore(
+        &self,
+        name: &str,
+        backup: Option<&Path>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.remove(name)?;
+
+        if let Some(backup) = backup {
+            let pkgdir = self.path.join(name);
+            fs::create_dir(&pkgdir)?;
+            sync_dir(&self.path)?;
+
+            for entry in fs::read_dir(backup)? {
+                let entry = entry?;
+                write_atomic(
+                    &pkgdir.join(entry.file_name()),
+                    &fs::read_to_string(entry.path())?,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Maps every file recorded as belonging to an installed package to the
+    /// names of the packages that it belongs to, of which there can be more
+    /// than one for directories.
+    pub fn file_owners(&self) -> Result<HashMap<String, Vec<String>>, Box<dyn std::error::Error>> {
+        let mut owners: HashMap<String, Vec<String>> = HashMap::new();
+
+        for package in self.packages()? {
+            for file in package.files.iter() {
+                owners
+                    .entry(file.to_string())
+                    .or_default()
+                    .push(package.name.clone());
+            }
+        }
+
+        Ok(owners)
+    }
+}
+
+/// Works out the root and database directories from `--root` and `--dbpath`,
+/// the database lives inside of the root unless it's been given explicitly.
+pub fn paths(cli: &ArgMatches) -> (PathBuf, PathBuf) {
+    let root = PathBuf::from(cli.value_of("root").unwrap_or(ROOT));
+    let dbpath = match cli.value_of("dbpath") {
+        Some(dbpath) => PathBuf::from(dbpath),
+        None => root.join(DBPATH),
+    };
+
+    (root, dbpath)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Writes the contents to a temporary file next to the destination and then
+/// renames it into place, so that readers only ever see the old or the new
+/// contents and never a partial write.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let filename = path.file_name().unwrap().to_str().unwrap();
+    let tmp = path.with_file_name(format!(".{}.tmp", filename));
+
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+
+    fs::rename(&tmp, path)?;
+    sync_dir(path.parent().unwrap())?;
+
+    Ok(())
+}
+
+fn sync_dir(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    File::open(path)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_package(name: &str, files: &[&str]) -> InstalledPackage {
+        InstalledPackage {
+            files: files.iter().map(|f| f.to_string()).collect(),
+            ..InstalledPackage::for_test(name, "1.0")
+        }
+    }
+
+    fn test_dbpath(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mpm-db-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn test_add_get_remove() {
+        let dbpath = test_dbpath("add");
+        let db = LocalDatabase::open(&dbpath).unwrap();
+        let package = test_package("foo", &["usr/", "usr/bin/", "usr/bin/foo"]);
+
+        // nothing is created until something is installed
+        assert!(db.packages().unwrap().is_empty());
+        assert_eq!(db.get("foo").unwrap(), None);
+        assert!(!dbpath.exists());
+
+        db.add(&package).unwrap();
+        assert_eq!(db.get("foo").unwrap(), Some(package));
+        assert_eq!(db.packages().unwrap().len(), 1);
+
+        db.remove("foo").unwrap();
+        assert_eq!(db.get("foo").unwrap(), None);
+        assert!(db.packages().unwrap().is_empty());
+
+        fs::remove_dir_all(&dbpath).unwrap();
+    }
+
+    #[test]
+    fn test_backup_restore() {
+        let dbpath = test_dbpath("backup");
+        let db = LocalDatabase::open(&dbpath).unwrap();
+        let backup = dbpath.join("backup");
+        let package = test_package("foo", &["usr/bin/foo"]);
+
+        assert!(!db.backup("foo", &backup).unwrap());
+
+        db.add(&package).unwrap();
+        db.set_mtree("foo", &Mtree::default()).unwrap();
+        assert!(db.backup("foo", &backup).unwrap());
+
+        db.add(&test_package("foo", &["usr/bin/bar"])).unwrap();
+        db.restore("foo", Some(&backup)).unwrap();
+        assert_eq!(db.get("foo").unwrap(), Some(package));
+        assert_eq!(db.mtree("foo").unwrap(), Some(Mtree::default()));
+
+        db.restore("foo", None).unwrap();
+        assert_eq!(db.get("foo").unwrap(), None);
+
+        fs::remove_dir_all(&dbpath).unwrap();
+    }
+
+    #[test]
+    fn test_scriptlets() {
+        let dbpath = test_dbpath("scriptlets");
+        let db = LocalDatabase::open(&dbpath).unwrap();
+        let scriptlets = Scriptlets {
+            pre_remove: Some(String::from("echo bye\n")),
+            ..Default::default()
+        };
+
+        assert!(db.set_scriptlets("foo", Some(&scriptlets)).is_err());
+
+        db.add(&test_package("foo", &[])).unwrap();
+        assert_eq!(db.scriptlets("foo").unwrap(), None);
+        db.set_scriptlets("foo", Some(&scriptlets)).unwrap();
+        assert_eq!(db.scriptlets("foo").unwrap(), Some(scriptlets));
+        db.set_scriptlets("foo", None).unwrap();
+        assert_eq!(db.scriptlets("foo").unwrap(), None);
+
+        fs::remove_dir_all(&dbpath).unwrap();
+    }
+
+    #[test]
+    fn test_file_owners() {
+        let dbpath = test_dbpath("owners");
+        let db = LocalDatabase::open(&dbpath).unwrap();
+        db.add(&test_package("foo", &["usr/bin/", "usr/bin/foo"]))
+            .unwrap();
+        db.add(&test_package("bar", &["usr/bin/", "usr/bin/bar"]))
+            .unwrap();
+
+        let owners = db.file_owners().unwrap();
+        assert_eq!(owners["usr/bin/foo"], vec!["foo"]);
+        assert_eq!(owners["usr/bin/bar"], vec!["bar"]);
+        assert_eq!(owners["usr/bin/"], vec!["bar", "foo"]);
+
+        fs::remove_dir_all(&dbpath).unwrap();
+    }
+
+    #[test]
+    fn test_full_version() {
+        let mut package = test_package("foo", &[]);
+        assert_eq!(package.full_version(), "1.0-1");
+        package.epoch = Some(2);
+        assert_eq!(package.full_version(), "2:1.0-1");
+    }
+}
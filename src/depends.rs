@@ -0,0 +1,542 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use super::db::InstalledPackage;
+use super::package::pkginfo::PackageInfo;
+use super::version::Version;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operator {
+    Lt,
+    Le,
+    Eq,
+    Ge,
+    Gt,
+}
+
+/// A dependency on another package, optionally constrained to a range of
+/// versions (e.g., `foo`, `foo>=1.2`, `bar<2`, `baz=1:3.0-1`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dependency {
+    pub name: String,
+    pub constraint: Option<(Operator, Version)>,
+}
+
+/// Anything that can satisfy a dependency, either directly by name or through
+/// one of the virtual packages that it provides.
+pub trait Provider {
+    fn name(&self) -> &str;
+    fn pkgver(&self) -> Version;
+    fn provides(&self) -> &[String];
+}
+
+impl Provider for PackageInfo {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn pkgver(&self) -> Version {
+        PackageInfo::pkgver(self)
+    }
+
+    fn provides(&self) -> &[String] {
+        &self.provides
+    }
+}
+
+impl Provider for InstalledPackage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn pkgver(&self) -> Version {
+        InstalledPackage::pkgver(self)
+    }
+
+    fn provides(&self) -> &[String] {
+        &self.provides
+    }
+}
+
+impl Dependency {
+    /// Checks whether the package satisfies this dependency, either itself or
+    /// through something that it provides. Like pacman, an unversioned
+    /// provision only satisfies an unversioned dependency.
+    pub fn satisfied_by_package<P: Provider>(&self, package: &P) -> bool {
+        if self.satisfied_by(package.name(), &package.pkgver()) {
+            return true;
+        }
+
+        package
+            .provides()
+            .iter()
+            .any(|provides| match provides.parse::<Dependency>() {
+                Ok(Dependency {
+                    name,
+                    constraint: Some((Operator::Eq, version)),
+                }) => self.satisfied_by(&name, &version),
+                Ok(Dependency {
+                    name,
+                    constraint: None,
+                }) => name == self.name && self.constraint.is_none(),
+                _ => false,
+            })
+    }
+
+    pub fn satisfied_by(&self, name: &str, version: &Version) -> bool {
+        if name != self.name {
+            return false;
+        }
+
+        match &self.constraint {
+            Some((operator, wanted)) => {
+                let ordering = version.vercmp(wanted);
+                match operator {
+                    Operator::Lt => ordering == Ordering::Less,
+                    Operator::Le => ordering != Ordering::Greater,
+                    Operator::Eq => ordering == Ordering::Equal,
+                    Operator::Ge => ordering != Ordering::Less,
+                    Operator::Gt => ordering == Ordering::Greater,
+                }
+            }
+            None => true,
+        }
+    }
+}
+
+impl FromStr for Dependency {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let idx = match s.find(['<', '>', '=']) {
+            Some(idx) => idx,
+            None => {
+                if s.is_empty() {
+                    return Err("empty dependency")?;
+                }
+
+                return Ok(Dependency {
+                    name: s.to_string(),
+                    constraint: None,
+                });
+            }
+        };
+
+        let name = &s[..idx];
+        let rest = &s[idx..];
+        if name.is_empty() {
+            return Err(format!("missing package name in dependency {}", s))?;
+        }
+
+        let (operator, version) = if let Some(version) = rest.strip_prefix(">=") {
+            (Operator::Ge, version)
+        } else if let Some(version) = rest.strip_prefix("<=") {
+            (Operator::Le, version)
+        } else if let Some(version) = rest.strip_prefix('>') {
+            (Operator::Gt, version)
+        } else if let Some(version) = rest.strip_prefix('<') {
+            (Operator::Lt, version)
+        } else {
+            (Operator::Eq, rest.strip_prefix('=').unwrap())
+        };
+
+        let version = match version.parse() {
+            Ok(version) => version,
+            Err(err) => return Err(format!("invalid dependency {}: {}", s, err))?,
+        };
+
+        Ok(Dependency {
+            name: name.to_string(),
+            constraint: Some((operator, version)),
+        })
+    }
+}
+
+impl std::fmt::Display for Dependency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        if let Some((operator, version)) = &self.constraint {
+            let operator = match operator {
+                Operator::Lt => "<",
+                Operator::Le => "<=",
+                Operator::Eq => "=",
+                Operator::Ge => ">=",
+                Operator::Gt => ">",
+            };
+            write!(f, "{}{}", operator, version)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn parse_all(depends: &[String]) -> Result<Vec<Dependency>, Box<dyn std::error::Error>> {
+    depends.iter().map(|d| d.parse()).collect()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Visiting,
+    Done,
+}
+
+/// A package that the resolver has picked, along with the dependency that it
+/// was picked for so that later conflicting requirements can point at it.
+struct Chosen<'a> {
+    state: State,
+    package: &'a PackageInfo,
+    depend: String,
+}
+
+/// Works out everything that needs to be installed for a set of targets from
+/// the packages that are available, skipping dependencies that are already
+/// satisfied by installed packages.
+pub struct Resolver<'a> {
+    available: &'a [PackageInfo],
+    installed: &'a [InstalledPackage],
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(available: &'a [PackageInfo], installed: &'a [InstalledPackage]) -> Self {
+        Resolver {
+            available,
+            installed,
+        }
+    }
+
+    /// Returns the packages to install in the order that they need to be
+    /// installed in, i.e., every package comes after all of its dependencies.
+    pub fn resolve(
+        &self,
+        targets: &[Dependency],
+    ) -> Result<Vec<&'a PackageInfo>, Box<dyn std::error::Error>> {
+        let mut order = Vec::new();
+        let mut states = HashMap::new();
+        let mut path = Vec::new();
+
+        for target in targets.iter() {
+            self.visit(target, None, &mut states, &mut path, &mut order)?;
+        }
+
+        Ok(order)
+    }
+
+    fn visit(
+        &self,
+        depend: &Dependency,
+        required_by: Option<&str>,
+        states: &mut HashMap<String, Chosen<'a>>,
+        path: &mut Vec<String>,
+        order: &mut Vec<&'a PackageInfo>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let package = self.find(depend, required_by)?;
+
+        match states.get(&package.name) {
+            // only one version of a package can be installed, so whatever we
+            // picked for something else has to do for this too
+            Some(chosen) if !depend.satisfied_by_package(chosen.package) => {
+                return Err(format!(
+                    "unable to satisfy {}{}, {}-{} was already picked for {}",
+                    depend,
+                    format_required_by(required_by),
+                    chosen.package.name,
+                    chosen.package.full_version(),
+                    chosen.depend
+                ))?;
+            }
+            Some(chosen) if chosen.state == State::Done => return Ok(()),
+            Some(_) => {
+                path.push(package.name.clone());
+                let start = path.iter().position(|p| p == &package.name).unwrap();
+                return Err(format!(
+                    "dependency cycle detected: {}",
+                    path[start..].join(" -> ")
+                ))?;
+            }
+            None => (),
+        }
+
+        let requirement = format!("{}{}", depend, format_required_by(required_by));
+        states.insert(
+            package.name.clone(),
+            Chosen {
+                state: State::Visiting,
+                package,
+                depend: requirement,
+            },
+        );
+        path.push(package.name.clone());
+
+        for depend in parse_all(&package.depends)?.iter() {
+            // something that we've already picked might satisfy it
+            if order.iter().any(|p| depend.satisfied_by_package(*p)) {
+                continue;
+            }
+
+            if self
+                .installed
+                .iter()
+                .any(|p| depend.satisfied_by_package(p))
+            {
+                continue;
+            }
+
+            self.visit(depend, Some(&package.name), states, path, order)?;
+        }
+
+        path.pop();
+        states.get_mut(&package.name).unwrap().state = State::Done;
+        order.push(package);
+
+        Ok(())
+    }
+
+    /// Finds the newest available package that satisfies the dependency,
+    /// preferring a package with the exact name over one that provides it.
+    /// Ties go to whichever package comes first.
+    fn find(
+        &self,
+        depend: &Dependency,
+        required_by: Option<&str>,
+    ) -> Result<&'a PackageInfo, Box<dyn std::error::Error>> {
+        let newest = self
+            .available
+            .iter()
+            .filter(|p| depend.satisfied_by(&p.name, &p.pkgver()))
+            .rev()
+            .max_by(|a, b| a.pkgver().cmp(&b.pkgver()));
+
+        if let Some(package) = newest {
+            return Ok(package);
+        }
+
+        let mut providers: Vec<&PackageInfo> = self
+            .available
+            .iter()
+            .filter(|p| depend.satisfied_by_package(*p))
+            .collect();
+        providers.sort_by(|a, b| a.name.cmp(&b.name));
+
+        if let Some(package) = providers.first() {
+            return Ok(package);
+        }
+
+        let required_by = format_required_by(required_by);
+
+        let candidates: Vec<String> = self
+            .available
+            .iter()
+            .filter(|p| p.name == depend.name)
+            .map(|p| p.full_version())
+            .chain(
+                self.installed
+                    .iter()
+                    .filter(|p| p.name == depend.name)
+                    .map(|p| format!("{} (installed)", p.full_version())),
+            )
+            .collect();
+
+        if candidates.is_empty() {
+            Err(format!("unable to find {}{}", depend, required_by))?
+        } else {
+            Err(format!(
+                "unable to satisfy {}{}, available versions: {}",
+                depend,
+                required_by,
+                candidates.join(", ")
+            ))?
+        }
+    }
+}
+
+fn format_required_by(required_by: Option<&str>) -> String {
+    match required_by {
+        Some(name) => format!(" (required by {})", name),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dep(s: &str) -> Dependency {
+        s.parse().unwrap()
+    }
+
+    fn info(name: &str, version: &str, depends: &[&str]) -> PackageInfo {
+        PackageInfo {
+            name: String::from(name),
+            base: String::from(name),
+            version: String::from(version),
+            release: 1,
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn names(packages: Vec<&PackageInfo>) -> Vec<String> {
+        packages
+            .iter()
+            .map(|p| format!("{}-{}", p.name, p.full_version()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(dep("foo").constraint, None);
+
+        let d = dep("foo>=1.2");
+        assert_eq!(d.name, "foo");
+        assert_eq!(d.constraint.as_ref().unwrap().0, Operator::Ge);
+        assert_eq!(d.constraint.as_ref().unwrap().1.to_string(), "1.2");
+
+        assert_eq!(dep("bar<2").constraint.unwrap().0, Operator::Lt);
+        assert_eq!(dep("bar<=2").constraint.unwrap().0, Operator::Le);
+        assert_eq!(dep("bar>2").constraint.unwrap().0, Operator::Gt);
+
+        let d = dep("baz=1:3.0-1");
+        assert_eq!(d.constraint.as_ref().unwrap().0, Operator::Eq);
+        assert_eq!(d.to_string(), "baz=1:3.0-1");
+
+        assert!("".parse::<Dependency>().is_err());
+        assert!(">=1.0".parse::<Dependency>().is_err());
+        assert!("foo>=".parse::<Dependency>().is_err());
+        assert!("foo=>1".parse::<Dependency>().is_err());
+    }
+
+    #[test]
+    fn test_satisfied_by() {
+        let v = |s: &str| s.parse::<Version>().unwrap();
+
+        assert!(dep("foo").satisfied_by("foo", &v("0.1-1")));
+        assert!(!dep("foo").satisfied_by("bar", &v("0.1-1")));
+        assert!(dep("foo>=1.2").satisfied_by("foo", &v("1.2-3")));
+        assert!(!dep("foo>=1.2").satisfied_by("foo", &v("1.1-3")));
+        assert!(dep("foo<2").satisfied_by("foo", &v("1.9-1")));
+        assert!(!dep("foo<2").satisfied_by("foo", &v("2.0-1")));
+        assert!(dep("foo=1:3.0-1").satisfied_by("foo", &v("1:3.0-1")));
+        assert!(!dep("foo=1:3.0-1").satisfied_by("foo", &v("1:3.0-2")));
+        assert!(!dep("foo=1:3.0-1").satisfied_by("foo", &v("3.0-1")));
+    }
+
+    #[test]
+    fn test_resolve_order() {
+        let available = vec![
+            info("app", "1.0", &["libfoo>=1.0", "libbar"]),
+            info("libfoo", "0.9", &[]),
+            info("libfoo", "1.1", &["libc"]),
+            info("libbar", "2.0", &["libc"]),
+            info("libc", "2.33", &[]),
+        ];
+
+        let resolver = Resolver::new(&available, &[]);
+        assert_eq!(
+            names(resolver.resolve(&[dep("app")]).unwrap()),
+            vec!["libc-2.33-1", "libfoo-1.1-1", "libbar-2.0-1", "app-1.0-1"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_prefers_first() {
+        let available = vec![info("app", "1.0", &[]), info("app", "1.0", &[])];
+        let resolver = Resolver::new(&available, &[]);
+        let resolved = resolver.resolve(&[dep("app=1.0-1")]).unwrap();
+        assert!(std::ptr::eq(resolved[0], &available[0]));
+    }
+
+    #[test]
+    fn test_resolve_skips_installed() {
+        let available = vec![info("app", "1.0", &["libc>=2"]), info("libc", "2.33", &[])];
+        let installed = vec![InstalledPackage::for_test("libc", "2.30")];
+
+        let resolver = Resolver::new(&available, &installed);
+        assert_eq!(
+            names(resolver.resolve(&[dep("app")]).unwrap()),
+            vec!["app-1.0-1"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_unsatisfiable() {
+        let available = vec![info("app", "1.0", &["libc>=3"]), info("libc", "2.33", &[])];
+        let resolver = Resolver::new(&available, &[]);
+        let err = resolver.resolve(&[dep("app")]).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "unable to satisfy libc>=3 (required by app), available versions: 2.33-1"
+        );
+
+        let err = resolver.resolve(&[dep("missing")]).unwrap_err().to_string();
+        assert_eq!(err, "unable to find missing");
+    }
+
+    #[test]
+    fn test_resolve_conflicting_versions() {
+        let available = vec![
+            info("app", "1.0", &["libfoo<1.0", "libbar"]),
+            info("libbar", "1.0", &["libfoo>=1.1"]),
+            info("libfoo", "0.9", &[]),
+            info("libfoo", "1.1", &[]),
+        ];
+        let resolver = Resolver::new(&available, &[]);
+        let err = resolver.resolve(&[dep("app")]).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "unable to satisfy libfoo>=1.1 (required by libbar), \
+             libfoo-0.9-1 was already picked for libfoo<1.0 (required by app)"
+        );
+
+        // the same goes for targets
+        let err = resolver
+            .resolve(&[dep("libfoo<1.0"), dep("libfoo>=1.1")])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "unable to satisfy libfoo>=1.1, libfoo-0.9-1 was already picked for libfoo<1.0"
+        );
+    }
+
+    #[test]
+    fn test_satisfied_by_provides() {
+        let mut dash = info("dash", "0.5", &[]);
+        dash.provides = vec![String::from("sh")];
+        let mut bash = info("bash", "5.1", &[]);
+        bash.provides = vec![String::from("sh=5.1")];
+
+        assert!(dep("sh").satisfied_by_package(&dash));
+        assert!(!dep("sh>=5").satisfied_by_package(&dash));
+        assert!(dep("sh").satisfied_by_package(&bash));
+        assert!(dep("sh>=5").satisfied_by_package(&bash));
+        assert!(!dep("sh>=6").satisfied_by_package(&bash));
+    }
+
+    #[test]
+    fn test_resolve_provides() {
+        let mut dash = info("dash", "0.5", &[]);
+        dash.provides = vec![String::from("sh")];
+        let available = vec![info("script", "1.0", &["sh"]), dash];
+
+        let resolver = Resolver::new(&available, &[]);
+        assert_eq!(
+            names(resolver.resolve(&[dep("script")]).unwrap()),
+            vec!["dash-0.5-1", "script-1.0-1"]
+        );
+    }
+
+    #[test]
+    fn test_resolve_cycle() {
+        let available = vec![
+            info("a", "1.0", &["b"]),
+            info("b", "1.0", &["c"]),
+            info("c", "1.0", &["a"]),
+        ];
+        let resolver = Resolver::new(&available, &[]);
+        let err = resolver.resolve(&[dep("a")]).unwrap_err().to_string();
+        assert_eq!(err, "dependency cycle detected: a -> b -> c -> a");
+    }
+}
@@ -1,35 +1,372 @@
 use std::cmp::min;
-use std::fs::File;
+use std::fs::{self, File, OpenOptions};
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 use futures_util::StreamExt;
-use hex;
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::Client;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::header::{
+    HeaderName, CONTENT_RANGE, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, LAST_MODIFIED,
+    RANGE,
+};
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use url::Url;
 
+/// The cache validators that a server sent along with a file, used to ask it
+/// for the file again only if it has changed since.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+/// A download that the server refused, kept as its own type so that we can
+/// tell the failures that are worth retrying apart from the rest.
+#[derive(Debug)]
+pub struct StatusError {
+    url: String,
+    status: StatusCode,
+}
+
+impl StatusError {
+    fn new(url: &str, status: StatusCode) -> Self {
+        StatusError {
+            url: url.to_string(),
+            status,
+        }
+    }
+}
+
+impl std::fmt::Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unable to download {}: {}", self.url, self.status)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+/// How many times to retry a download that failed with what's likely a
+/// temporary problem, waiting `delay` before the first retry and twice as
+/// long before each one after that.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+/// A file to download, from the first of `urls` that works.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Download {
+    pub urls: Vec<String>,
+    pub dest: PathBuf,
+    /// What the file has to match for a download from a mirror to count.
+    pub sha256sum: Option<String>,
+}
+
+/// Downloads everything in `downloads`, `parallel` of them at a time, with a
+/// progress bar for each. A failed download doesn't stop the others and
+/// every failure is returned at the end.
+pub async fn download_all(
+    client: &Client,
+    downloads: &[Download],
+    parallel: usize,
+    retry: &Retry,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if downloads.is_empty() {
+        return Ok(());
+    }
+
+    // the bars all have to exist before we start drawing them or it'll stop
+    // as soon as the first few are finished. they aren't drawn at all when
+    // there's no terminal, which the bars themselves have to know about so
+    // that anything printed through them isn't lost
+    let hidden = ProgressDrawTarget::stderr().is_hidden();
+    let multi = Arc::new(MultiProgress::new());
+    let bars: Vec<ProgressBar> = downloads
+        .iter()
+        .map(|download| {
+            let pb = if hidden {
+                ProgressBar::hidden()
+            } else {
+                multi.add(ProgressBar::new(0))
+            };
+            pb.set_style(ProgressStyle::default_bar().template("{msg}"));
+            pb.set_message(format!("Waiting: {}", download_name(download)));
+            pb
+        })
+        .collect();
+    let drawing = {
+        let multi = multi.clone();
+        tokio::task::spawn_blocking(move || multi.join())
+    };
+
+    let errors: Vec<String> = futures_util::stream::iter(downloads.iter().zip(bars))
+        .map(|(download, pb)| async move {
+            let result = download_from_mirrors(client, download, retry, &pb).await;
+            match result {
+                Ok(_) => {
+                    pb.finish_with_message(format!("Downloaded: {}", download_name(download)));
+                    None
+                }
+                Err(err) => {
+                    pb.abandon_with_message(format!("Failed: {}", download_name(download)));
+                    Some(err.to_string())
+                }
+            }
+        })
+        .buffered(parallel)
+        .filter_map(|err| async move { err })
+        .collect()
+        .await;
+
+    drawing.await??;
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+
+    Ok(())
+}
+
+fn download_name(download: &Download) -> String {
+    download
+        .dest
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Downloads `url` to `dest` through a `.part` file. An earlier download that
+/// was interrupted is resumed from where it stopped as long as the server can
+/// tell us that the file hasn't changed since.
+///
+/// With `if_modified` the server is asked to only send the file if it has
+/// changed since it sent us those validators, returning `None` if it hasn't.
+/// Otherwise the validators of the file that was downloaded are returned.
+///
+/// With `sha256sum` the download has to match it before it replaces `dest`.
 pub async fn download_file(
     client: &Client,
     url: &str,
-    dest: &str,
-    overwrite: bool,
+    dest: &Path,
+    if_modified: Option<&Validators>,
+    sha256sum: Option<&str>,
+    pb: &ProgressBar,
+) -> Result<Option<Validators>, Box<dyn std::error::Error>> {
+    let part = part_path(dest);
+    let saved = part_validators_path(dest);
+    let mut resume = match (fs::metadata(&part), read_validators(&saved)) {
+        (Ok(meta), Some(validators)) if meta.len() > 0 => validators
+            .if_range()
+            .map(|validator| (meta.len(), validator.to_string())),
+        _ => None,
+    };
+
+    let request = |resume: &Option<(u64, String)>| {
+        let mut request = client.get(url);
+        if let Some(validators) = if_modified {
+            if let Some(etag) = &validators.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &validators.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+        if let Some((offset, validator)) = resume {
+            request = request
+                .header(RANGE, format!("bytes={}-", offset))
+                .header(IF_RANGE, validator);
+        }
+        request
+    };
+
+    let mut response = request(&resume).send().await?;
+    if resume.is_some() && response.status() == StatusCode::RANGE_NOT_SATISFIABLE {
+        // whatever we have doesn't line up with the file any more
+        resume = None;
+        response = request(&resume).send().await?;
+    }
+    if if_modified.is_some() && response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(None);
+    }
+    if !response.status().is_success() {
+        return Err(StatusError::new(url, response.status()).into());
+    }
+
+    // the server sends the whole file instead if it doesn't do ranges or if
+    // the file changed
+    let validators = Validators {
+        etag: header_value(&response, ETAG),
+        last_modified: header_value(&response, LAST_MODIFIED),
+    };
+    let offset = match (&resume, response.status()) {
+        (Some((offset, _)), StatusCode::PARTIAL_CONTENT) => {
+            if content_range_start(&response) != Some(*offset) {
+                return Err(format!("unable to download {}: unexpected range", url).into());
+            }
+            *offset
+        }
+        _ => {
+            if validators.if_range().is_some() {
+                fs::write(&saved, serde_yaml::to_string(&validators)?)?;
+            } else if saved.exists() {
+                fs::remove_file(&saved)?;
+            }
+            0
+        }
+    };
+
+    let filename = get_url_basename(url)?;
+    write_response(response, &filename, dest, offset, sha256sum, pb).await?;
+
+    if saved.exists() {
+        fs::remove_file(&saved)?;
+    }
+
+    Ok(Some(validators))
+}
+
+/// Downloads `url` to `dest`, retrying after connection problems and server
+/// errors instead of giving up straight away.
+async fn fetch_with_retry(
+    client: &Client,
+    url: &str,
+    dest: &Path,
+    sha256sum: Option<&str>,
+    retry: &Retry,
+    pb: &ProgressBar,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let filename = get_url_basename(url).unwrap();
+    let mut delay = retry.delay;
+    let mut attempt = 0;
 
-    if Path::new(&filename).exists() && !overwrite {
-        return Ok(());
+    loop {
+        match download_file(client, url, dest, None, sha256sum, pb).await {
+            Ok(_) => return Ok(()),
+            Err(err) if attempt < retry.retries && is_transient(err.as_ref()) => {
+                attempt += 1;
+                let warning = format!(
+                    "warning: {}, retrying in {}ms ({}/{})",
+                    err,
+                    delay.as_millis(),
+                    attempt,
+                    retry.retries
+                );
+                // printing past a bar that's being drawn would get drawn over,
+                // but a hidden one doesn't print anything at all
+                if pb.is_hidden() {
+                    eprintln!("{}", warning);
+                } else {
+                    pb.println(warning);
+                }
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(err) if attempt > 0 => {
+                return Err(format!("{} (gave up after {} attempts)", err, attempt + 1).into())
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Downloads the file from the first of its urls that works, trying each one
+/// in order and listing why every one of them failed if none do.
+async fn download_from_mirrors(
+    client: &Client,
+    download: &Download,
+    retry: &Retry,
+    pb: &ProgressBar,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let dest = &download.dest;
+    let sha256sum = download.sha256sum.as_deref();
+
+    let mut errors = Vec::new();
+    for url in download.urls.iter() {
+        match fetch_with_retry(client, url, dest, sha256sum, retry, pb).await {
+            Ok(_) => return Ok(()),
+            Err(err) => errors.push(format!("{}: {}", url, err)),
+        }
     }
 
-    let response = client.get(url).send().await?;
+    Err(format!(
+        "unable to download {}:\n  {}",
+        download_name(download),
+        errors.join("\n  ")
+    )
+    .into())
+}
+
+/// Whether a failed download might work if we try again, which is the case
+/// for network problems and for servers that are having problems of their
+/// own but not for requests that the server refused.
+fn is_transient(err: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(err) = err.downcast_ref::<StatusError>() {
+        return err.status.is_server_error();
+    }
 
+    match err.downcast_ref::<reqwest::Error>() {
+        Some(err) => err.is_connect() || err.is_timeout() || err.is_request() || err.is_body(),
+        None => false,
+    }
+}
+
+impl Validators {
+    /// The validator to resume a download with, which has to be a strong one.
+    fn if_range(&self) -> Option<&String> {
+        match &self.etag {
+            Some(etag) if !etag.starts_with("W/") => Some(etag),
+            _ => self.last_modified.as_ref(),
+        }
+    }
+}
+
+fn header_value(response: &Response, name: HeaderName) -> Option<String> {
+    let value = response.headers().get(name)?.to_str().ok()?;
+    Some(value.to_string())
+}
+
+/// Where the validators for a partial download are kept, so that resuming it
+/// can check that it's still the same file.
+fn part_validators_path(dest: &Path) -> PathBuf {
+    let mut path = part_path(dest).into_os_string();
+    path.push(".validators");
+    PathBuf::from(path)
+}
+
+fn read_validators(path: &Path) -> Option<Validators> {
+    serde_yaml::from_reader(File::open(path).ok()?).ok()
+}
+
+/// The offset of the first byte in a partial response, from a `Content-Range`
+/// like `bytes 100-999/1000`.
+fn content_range_start(response: &Response) -> Option<u64> {
+    let range = header_value(response, CONTENT_RANGE)?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
+}
+
+/// Writes the body of the response to `dest`, appending it to what's already
+/// been downloaded if it starts at `offset`. A download that doesn't match
+/// `sha256sum` is thrown away without touching `dest`.
+async fn write_response(
+    response: Response,
+    filename: &str,
+    dest: &Path,
+    offset: u64,
+    sha256sum: Option<&str>,
+    pb: &ProgressBar,
+) -> Result<(), Box<dyn std::error::Error>> {
     let total_bytes = match response.content_length() {
-        Some(tb) => tb,
+        Some(length) => offset + length,
         None => 0,
     };
 
-    let pb = ProgressBar::new(total_bytes);
+    pb.set_length(total_bytes);
 
     if total_bytes == 0 {
         pb.set_style(
@@ -44,18 +381,26 @@ pub async fn download_file(
 
     pb.set_message(format!("Downloading: {}", filename));
 
-    // TODO: handle destination correctly
-    let mut file = match File::create(filename) {
+    // nothing is ever written to `dest` directly so that an interrupted
+    // download can't be mistaken for a finished one
+    let part = part_path(dest);
+    let opened = if offset > 0 {
+        OpenOptions::new().append(true).open(&part)
+    } else {
+        File::create(&part)
+    };
+    let mut file = match opened {
         Ok(f) => f,
         Err(err) => return Err(Box::new(err)),
     };
 
-    let mut downloaded_bytes: u64 = 0;
+    let mut downloaded_bytes: u64 = offset;
+    pb.set_position(offset);
     let mut stream = response.bytes_stream();
 
     while let Some(slice) = stream.next().await {
         let chunk = slice?;
-        file.write(&chunk)?;
+        file.write_all(&chunk)?;
         if total_bytes == 0 {
             pb.inc(chunk.len() as u64);
         } else {
@@ -64,17 +409,40 @@ pub async fn download_file(
         }
     }
 
-    pb.finish_with_message(format!("Done"));
+    file.sync_all()?;
+    if let Some(sha256sum) = sha256sum {
+        if !file_sha256sum_matches(part.to_str().unwrap(), sha256sum) {
+            fs::remove_file(&part)?;
+            return Err("checksum mismatch".into());
+        }
+    }
+    fs::rename(&part, dest)?;
 
     Ok(())
 }
 
-pub fn file_sha256sum_matches(path: &str, expected: &str) -> bool {
-    let mut file = File::open(path).unwrap();
+/// Where a download to `dest` is written until it's finished.
+pub fn part_path(dest: &Path) -> PathBuf {
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+pub fn file_sha256sum(path: &str) -> std::io::Result<String> {
+    let mut file = File::open(path)?;
     let mut sum = Sha256::new();
-    std::io::copy(&mut file, &mut sum).unwrap();
+    std::io::copy(&mut file, &mut sum)?;
     let result = sum.finalize();
-    hex::encode(result) == expected
+    Ok(hex::encode(result))
+}
+
+/// Checks the file against the expected checksum, a file that can't be read
+/// doesn't match anything.
+pub fn file_sha256sum_matches(path: &str, expected: &str) -> bool {
+    match file_sha256sum(path) {
+        Ok(sum) => sum == expected,
+        Err(_) => false,
+    }
 }
 
 pub fn get_url_basename(url: &str) -> Result<String, Box<dyn std::error::Error>> {
@@ -91,6 +459,10 @@ pub fn get_url_basename(url: &str) -> Result<String, Box<dyn std::error::Error>>
 mod tests {
     use super::*;
 
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::test_server::{Response, TestServer};
+
     #[test]
     fn good_parse_example() {
         assert_eq!(
@@ -99,14 +471,323 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_download_file() {
+        let dir = std::env::temp_dir().join(format!("mpm-downloader-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let server = TestServer::start(|request| match request.path.as_str() {
+            "/file.txt" => Response::new(200, b"contents"),
+            "/current.txt" => match request.headers.get("if-none-match") {
+                Some(etag) if etag == "\"v1\"" => Response::new(304, b""),
+                _ => Response::new(200, b"current").header("ETag", "\"v1\""),
+            },
+            _ => Response::new(404, b"not found"),
+        });
+        let client = Client::builder().no_proxy().build().unwrap();
+        let pb = ProgressBar::hidden();
+        let dest = dir.join("file.txt");
+
+        // a leftover partial download doesn't count as the file
+        fs::write(part_path(&dest), "cont").unwrap();
+        download_file(
+            &client,
+            &format!("{}/file.txt", server.url),
+            &dest,
+            None,
+            None,
+            &pb,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"contents");
+        assert!(!part_path(&dest).exists());
+
+        // a download that doesn't match its checksum never replaces the file
+        let err = download_file(
+            &client,
+            &format!("{}/file.txt", server.url),
+            &dest,
+            None,
+            Some("0000"),
+            &pb,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.to_string(), "checksum mismatch");
+        assert_eq!(fs::read(&dest).unwrap(), b"contents");
+        assert!(!part_path(&dest).exists());
+
+        let missing = dir.join("missing.txt");
+        assert!(download_file(
+            &client,
+            &format!("{}/missing.txt", server.url),
+            &missing,
+            None,
+            None,
+            &pb
+        )
+        .await
+        .is_err());
+        assert!(!missing.exists());
+
+        // conditional requests only download the file if it changed
+        let url = format!("{}/current.txt", server.url);
+        let current = dir.join("current.txt");
+        let old = Validators {
+            etag: Some(String::from("\"v0\"")),
+            last_modified: None,
+        };
+        let validators = download_file(&client, &url, &current, Some(&old), None, &pb)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(validators.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(fs::read(&current).unwrap(), b"current");
+
+        fs::remove_file(&current).unwrap();
+        let unchanged = download_file(&client, &url, &current, Some(&validators), None, &pb)
+            .await
+            .unwrap();
+        assert_eq!(unchanged, None);
+        assert!(!current.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume() {
+        let dir =
+            std::env::temp_dir().join(format!("mpm-downloader-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let server = TestServer::start(|request| {
+            let body = b"0123456789";
+            let current = request.headers.get("if-range").map(|v| v.as_str()) == Some("\"v2\"");
+            match (request.path.as_str(), request.headers.get("range")) {
+                ("/file.txt", Some(range)) if current => {
+                    let start: usize = range["bytes=".len()..range.len() - 1].parse().unwrap();
+                    if start >= body.len() {
+                        return Response::new(416, b"");
+                    }
+                    Response::new(206, &body[start..])
+                        .header("Content-Range", &format!("bytes {}-9/10", start))
+                        .header("ETag", "\"v2\"")
+                }
+                ("/file.txt", _) => Response::new(200, body).header("ETag", "\"v2\""),
+                // no validators so nothing can be resumed
+                _ => Response::new(200, b"abcdef"),
+            }
+        });
+        let client = Client::builder().no_proxy().build().unwrap();
+        let pb = ProgressBar::hidden();
+        let url = format!("{}/file.txt", server.url);
+        let dest = dir.join("file.txt");
+
+        let interrupt = |contents: &str, etag: &str| {
+            fs::write(part_path(&dest), contents).unwrap();
+            let validators = Validators {
+                etag: Some(String::from(etag)),
+                last_modified: None,
+            };
+            fs::write(
+                part_validators_path(&dest),
+                serde_yaml::to_string(&validators).unwrap(),
+            )
+            .unwrap();
+        };
+
+        interrupt("0123", "\"v2\"");
+        download_file(&client, &url, &dest, None, None, &pb)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789");
+        assert_eq!(server.requests()[0].headers["range"], "bytes=4-");
+        assert!(!part_validators_path(&dest).exists());
+
+        // the file changed since so it has to start over
+        interrupt("abcd", "\"v1\"");
+        download_file(&client, &url, &dest, None, None, &pb)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789");
+
+        // there's nothing left to fetch from a range that's past the end
+        interrupt("0123456789xyz", "\"v2\"");
+        download_file(&client, &url, &dest, None, None, &pb)
+            .await
+            .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"0123456789");
+        assert!(!part_path(&dest).exists());
+
+        // without the validators there's no telling what the partial
+        // download was of
+        fs::remove_file(&dest).unwrap();
+        fs::write(part_path(&dest), "01").unwrap();
+        download_file(&client, &url, &dest, None, None, &pb)
+            .await
+            .unwrap();
+        let requests = server.requests();
+        assert!(!requests.last().unwrap().headers.contains_key("range"));
+
+        // and a server that doesn't send any leaves nothing to resume with
+        let other = dir.join("other.txt");
+        fs::write(part_path(&other), "abc").unwrap();
+        download_file(
+            &client,
+            &format!("{}/other.txt", server.url),
+            &other,
+            None,
+            None,
+            &pb,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&other).unwrap(), b"abcdef");
+        assert!(!server
+            .requests()
+            .last()
+            .unwrap()
+            .headers
+            .contains_key("range"));
+        assert!(!part_validators_path(&other).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_retry() {
+        let dir = std::env::temp_dir().join(format!("mpm-downloader-retry-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        // fails twice before it works
+        let count = AtomicUsize::new(0);
+        let flaky = TestServer::start(move |_| match count.fetch_add(1, Ordering::SeqCst) {
+            0 | 1 => Response::new(503, b"busy"),
+            _ => Response::new(200, b"contents"),
+        });
+        let missing = TestServer::start(|_| Response::new(404, b"not found"));
+        let client = Client::builder().no_proxy().build().unwrap();
+        let pb = ProgressBar::hidden();
+        let dest = dir.join("file.txt");
+
+        let retry = Retry {
+            retries: 1,
+            delay: Duration::from_millis(1),
+        };
+        let err = fetch_with_retry(
+            &client,
+            &format!("{}/file.txt", flaky.url),
+            &dest,
+            None,
+            &retry,
+            &pb,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("503"));
+        assert!(err.to_string().contains("gave up after 2 attempts"));
+
+        fetch_with_retry(
+            &client,
+            &format!("{}/file.txt", flaky.url),
+            &dest,
+            None,
+            &retry,
+            &pb,
+        )
+        .await
+        .unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"contents");
+        assert_eq!(flaky.requests().len(), 3);
+
+        // there's no point in asking again for something that isn't there
+        let err = fetch_with_retry(
+            &client,
+            &format!("{}/file.txt", missing.url),
+            &dest,
+            None,
+            &retry,
+            &pb,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().ends_with("404 Not Found"));
+        assert_eq!(missing.requests().len(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_all() {
+        let dir = std::env::temp_dir().join(format!("mpm-downloader-all-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let broken = TestServer::start(|_| Response::new(500, b"broken"));
+        let missing = TestServer::start(|_| Response::new(404, b"not found"));
+        let corrupt = TestServer::start(|_| Response::new(200, b"corrupt"));
+        let working = TestServer::start(|request| Response::new(200, request.path.as_bytes()));
+        let client = Client::builder().no_proxy().build().unwrap();
+        let retry = Retry {
+            retries: 2,
+            delay: Duration::from_millis(1),
+        };
+
+        fs::write(dir.join("expected"), "/b.txt").unwrap();
+        let sha256sum = file_sha256sum(dir.join("expected").to_str().unwrap()).unwrap();
+
+        let download = |name: &str, servers: &[&TestServer], sha256sum: Option<&String>| Download {
+            urls: servers
+                .iter()
+                .map(|server| format!("{}/{}", server.url, name))
+                .collect(),
+            dest: dir.join(name),
+            sha256sum: sha256sum.cloned(),
+        };
+
+        let downloads = vec![
+            download("a.txt", &[&broken, &missing, &working], None),
+            download("b.txt", &[&corrupt, &working], Some(&sha256sum)),
+            download("c.txt", &[&working], None),
+        ];
+        download_all(&client, &downloads, 2, &retry).await.unwrap();
+        assert_eq!(fs::read(dir.join("a.txt")).unwrap(), b"/a.txt");
+        assert_eq!(fs::read(dir.join("b.txt")).unwrap(), b"/b.txt");
+        assert_eq!(fs::read(dir.join("c.txt")).unwrap(), b"/c.txt");
+        assert_eq!(broken.requests().len(), 3);
+
+        // every download is attempted and every failure reported
+        let downloads = vec![
+            download("d.txt", &[&broken, &missing], None),
+            download("e.txt", &[&working], None),
+            download("f.txt", &[&corrupt], Some(&sha256sum)),
+        ];
+        let err = download_all(&client, &downloads, 1, &retry)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("unable to download d.txt:\n"));
+        assert!(err.contains(&format!("{}: unable to download", downloads[0].urls[0])));
+        assert!(err.contains("500 Internal Server Error (gave up after 3 attempts)"));
+        assert!(err.contains(&format!("{}: unable to download", downloads[0].urls[1])));
+        assert!(err.contains("404 Not Found"));
+        assert!(err.contains(&format!(
+            "unable to download f.txt:\n  {}: checksum mismatch",
+            downloads[2].urls[0]
+        )));
+        assert!(dir.join("e.txt").exists());
+        assert!(!dir.join("d.txt").exists());
+        assert!(!dir.join("f.txt").exists());
+        assert!(!part_path(&dir.join("f.txt")).exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_known_hash() {
-        assert_eq!(
-            file_sha256sum_matches(
-                "tests/fixtures/src.tar.gz",
-                "b6492e004ca58d23bb38e9ea50dab9698edb49b759777143a9105fca58597125"
-            ),
-            true
-        );
+        assert!(file_sha256sum_matches(
+            "tests/fixtures/src.tar.gz",
+            "b6492e004ca58d23bb38e9ea50dab9698edb49b759777143a9105fca58597125"
+        ));
     }
 }
@@ -1,4 +1,717 @@
-pub fn run() -> Result<(), Box<dyn std::error::Error>> {
-    println!("TODO: install");
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+
+use clap::ArgMatches;
+use subprocess::{Exec, Redirection};
+
+use super::db::{self, InstallReason, InstalledPackage};
+use super::depends::{self, Dependency, Operator, Resolver};
+use super::downloader;
+use super::package::mtree::{EntryType, Mtree, MTREE_FILE};
+use super::package::pkginfo::{PackageInfo, PKGINFO_FILE};
+use super::package::scriptlet::{Hook, Scriptlets, INSTALL_FILE};
+use super::remove;
+use super::transaction::Transaction;
+
+static METADATA_FILES: [&str; 3] = [PKGINFO_FILE, MTREE_FILE, INSTALL_FILE];
+
+/// What to do with a file marked for backup when installing a new copy of it.
+#[derive(Debug, PartialEq)]
+enum Merge {
+    /// Replace whatever is there with the new copy.
+    Install,
+    /// Leave the copy on disk alone.
+    Keep,
+    /// Leave the copy on disk alone and put the new one next to it.
+    InstallNew,
+}
+
+pub fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let (root, dbpath) = db::paths(cli);
+    let mut txn = Transaction::begin(&root, &dbpath)?;
+
+    let archives: Vec<&str> = cli.values_of("package").unwrap().collect();
+    let infos = archives
+        .iter()
+        .map(|archive| PackageInfo::from_archive(archive))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let reason = if cli.is_present("asdeps") {
+        Some(InstallReason::Dependency)
+    } else {
+        None
+    };
+
+    if cli.is_present("nodeps") {
+        for archive in archives.iter() {
+            install_package(&mut txn, archive, reason)?;
+        }
+
+        return txn.commit();
+    }
+
+    // the only packages available to satisfy dependencies are the ones that
+    // we were given, everything else needs to already be installed
+    let installed = txn.localdb().packages()?;
+    let targets: Vec<Dependency> = infos
+        .iter()
+        .map(|info| Dependency {
+            name: info.name.clone(),
+            constraint: Some((Operator::Eq, info.pkgver())),
+        })
+        .collect();
+
+    for info in Resolver::new(&infos, &installed).resolve(&targets)? {
+        let idx = infos.iter().position(|i| std::ptr::eq(i, info)).unwrap();
+        install_package(&mut txn, archives[idx], reason)?;
+    }
+
+    txn.commit()
+}
+
+/// Installs the package archive, recording it as installed for `reason` or,
+/// if that isn't given, for the same reason as the package that it upgrades
+/// or replaces (or explicitly if there isn't one).
+pub fn install_package(
+    txn: &mut Transaction,
+    archive: &str,
+    reason: Option<InstallReason>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let info = PackageInfo::from_archive(archive)?;
+    let mtree = Mtree::from_archive(archive)?;
+    let scriptlets = Scriptlets::from_archive(archive)?;
+    let mut files = archive_files(archive)?;
+    let name = &info.name;
+
+    let installed = txn.localdb().packages()?;
+    let replaced = check_conflicts(&info, &installed)?;
+
+    // we're allowed to overwrite our own files (e.g., reinstalling the same
+    // package) and those of any package that we're replacing but nothing
+    // that belongs to another package or that was put there by hand
+    let mut allowed: Vec<&str> = replaced.iter().map(|p| p.name.as_str()).collect();
+    allowed.push(name);
+
+    let owners = txn.localdb().file_owners()?;
+    let conflicts = find_conflicts(txn.root(), &allowed, &files, &owners);
+    if !conflicts.is_empty() {
+        return Err(format!(
+            "{} conflicts with existing files:\n  {}",
+            name,
+            conflicts.join("\n  ")
+        )
+        .into());
+    }
+
+    let old = txn.localdb().get(name)?;
+    match &old {
+        Some(old) => {
+            let action = match info.pkgver().vercmp(&old.pkgver()) {
+                Ordering::Greater => "upgrading",
+                Ordering::Equal => "reinstalling",
+                Ordering::Less => "downgrading",
+            };
+            println!(
+                "{} {} ({} -> {})",
+                action,
+                name,
+                old.full_version(),
+                info.full_version()
+            )
+        }
+        None => println!("installing {} {}", name, info.full_version()),
+    }
+
+    let reason = match (reason, &old, replaced.first()) {
+        (Some(reason), _, _) => reason,
+        (None, Some(old), _) => old.reason,
+        (None, None, Some(replaced)) => replaced.reason,
+        (None, None, None) => InstallReason::Explicit,
+    };
+
+    for old in replaced.iter() {
+        println!("{} replaces {}", name, old.name);
+        remove::remove_package(txn, old)?;
+    }
+
+    // reinstalls and downgrades count as upgrades as far as the scriptlets
+    // are concerned, they just need to know that something was there before
+    let (pre, post) = match old {
+        Some(_) => (Hook::PreUpgrade, Hook::PostUpgrade),
+        None => (Hook::PreInstall, Hook::PostInstall),
+    };
+    let old_version = old.as_ref().map(|old| old.full_version());
+    let new_version = info.full_version();
+
+    if let Some(scriptlets) = &scriptlets {
+        scriptlets.run(
+            pre,
+            txn.root(),
+            name,
+            old_version.as_deref(),
+            Some(&new_version),
+        )?;
+    }
+
+    // everything gets extracted somewhere out of the way first so that a
+    // broken archive can't leave us with half a package, the metadata lives
+    // at the root of the archive but obviously isn't part of the package
+    let staging = txn.staging_dir(name)?;
+    let mut extract = Exec::cmd("bsdtar")
+        .arg("-xpf")
+        .arg(archive)
+        .arg("-C")
+        .arg(&staging);
+
+    for metadata in METADATA_FILES.iter() {
+        extract = extract.arg("--exclude").arg(format!("^{}", metadata));
+    }
+
+    let extract = extract
+        .stderr(Redirection::Merge)
+        .stdout(Redirection::Pipe)
+        .capture()?;
+
+    if !extract.success() {
+        return Err(format!(
+            "unable to extract {}: {}",
+            archive,
+            extract.stdout_str().trim()
+        )
+        .into());
+    }
+
+    // only regular files can be merged with an admin's changes
+    let mut backup = BTreeMap::new();
+    for file in info.backup.iter() {
+        let staged = staging.join(file);
+        if staged.is_file() {
+            backup.insert(
+                file.clone(),
+                downloader::file_sha256sum(staged.to_str().unwrap())?,
+            );
+        }
+    }
+    let original = match &old {
+        Some(old) => original_checksums(txn, old)?,
+        None => HashMap::new(),
+    };
+
+    // parents sort before their children
+    files.sort();
+    for file in files.iter() {
+        let path = file.trim_end_matches('/');
+        let staged = staging.join(path);
+        let meta = fs::symlink_metadata(&staged)?;
+
+        if meta.is_dir() {
+            txn.create_dir(path, &meta)?;
+            continue;
+        }
+
+        let new = match backup.get(path) {
+            Some(new) => new,
+            None => {
+                txn.install_file(&staged, path)?;
+                continue;
+            }
+        };
+
+        let target = txn.root().join(path);
+        let current = if target.is_file() {
+            Some(downloader::file_sha256sum(target.to_str().unwrap())?)
+        } else {
+            None
+        };
+
+        match merge_backup(
+            original.get(path).map(|o| o.as_str()),
+            current.as_deref(),
+            new,
+        ) {
+            Merge::Install => txn.install_file(&staged, path)?,
+            Merge::Keep => (),
+            Merge::InstallNew => {
+                let mpmnew = format!("{}.mpmnew", path);
+                println!(
+                    "warning: {} installed as {}",
+                    path,
+                    txn.root().join(&mpmnew).display()
+                );
+                txn.install_file(&staged, &mpmnew)?;
+            }
+        }
+    }
+
+    // if we replaced an older version of the package clean up anything that
+    // it had that the new version no longer ships
+    if let Some(old) = &old {
+        for file in old.files.iter() {
+            if !file.ends_with('/')
+                && !files.contains(file)
+                && fs::symlink_metadata(txn.root().join(file)).is_ok()
+            {
+                remove::remove_file(txn, old, file)?;
+            }
+        }
+    }
+
+    txn.add_package(
+        &InstalledPackage {
+            name: info.name.clone(),
+            epoch: info.epoch,
+            version: info.version.clone(),
+            release: info.release,
+            description: info.description.clone(),
+            url: info.url.clone(),
+            arch: info.arch.clone(),
+            builddate: info.builddate,
+            size: info.size,
+            license: info.license.clone(),
+            depends: info.depends.clone(),
+            provides: info.provides.clone(),
+            conflicts: info.conflicts.clone(),
+            replaces: info.replaces.clone(),
+            install_date: db::now(),
+            reason,
+            files,
+            backup,
+        },
+        &mtree,
+        scriptlets.as_ref(),
+    )?;
+
+    // by now the package is installed, a failure here is for the admin to
+    // sort out rather than a reason to back everything out
+    if let Some(scriptlets) = &scriptlets {
+        if let Err(err) = scriptlets.run(
+            post,
+            txn.root(),
+            name,
+            old_version.as_deref(),
+            Some(&new_version),
+        ) {
+            eprintln!("warning: {}", err);
+        }
+    }
+
     Ok(())
 }
+
+/// The checksums of the installed version's files as it shipped them, which
+/// is what tells us whether an admin has changed them since.
+fn original_checksums(
+    txn: &Transaction,
+    package: &InstalledPackage,
+) -> Result<HashMap<String, String>, Box<dyn std::error::Error>> {
+    // files that only became backup files in the new version can still be
+    // checked against the old manifest
+    let mut original: HashMap<String, String> = match txn.localdb().mtree(&package.name)? {
+        Some(mtree) => mtree
+            .entries
+            .into_iter()
+            .filter(|e| e.kind == EntryType::File && e.sha256.is_some())
+            .map(|e| (e.path, e.sha256.unwrap()))
+            .collect(),
+        None => HashMap::new(),
+    };
+    original.extend(package.backup.clone());
+
+    Ok(original)
+}
+
+/// Decides what to do with a file marked for backup by comparing the
+/// checksums of the copy that the installed version shipped, the copy on disk
+/// and the copy in the new version. If the admin hasn't changed it then it
+/// gets replaced, if we haven't changed it then their copy is kept, and if
+/// both of us have then the new copy goes next to theirs.
+fn merge_backup(original: Option<&str>, current: Option<&str>, new: &str) -> Merge {
+    match (original, current) {
+        (_, None) => Merge::Install,
+        (_, Some(current)) if current == new => Merge::Keep,
+        (Some(original), Some(current)) if original == current => Merge::Install,
+        (Some(original), Some(_)) if original == new => Merge::Keep,
+        _ => Merge::InstallNew,
+    }
+}
+
+fn archive_files(archive: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut source = File::open(archive)?;
+    match compress_tools::list_archive_files(&mut source) {
+        Ok(files) => Ok(files
+            .into_iter()
+            .map(|f| f.trim_start_matches("./").to_string())
+            .filter(|f| !f.is_empty() && !METADATA_FILES.contains(&f.as_str()))
+            .collect()),
+        Err(err) => Err(format!("unable to read {}: {}", archive, err))?,
+    }
+}
+
+/// Checks the package that we're about to install against everything that's
+/// already installed, returning the installed packages that it replaces or an
+/// error if it conflicts with anything that it doesn't replace.
+fn check_conflicts<'a>(
+    info: &PackageInfo,
+    installed: &'a [InstalledPackage],
+) -> Result<Vec<&'a InstalledPackage>, Box<dyn std::error::Error>> {
+    let replaces = depends::parse_all(&info.replaces)?;
+    let conflicts = depends::parse_all(&info.conflicts)?;
+    let mut replaced = Vec::new();
+    let mut errors = Vec::new();
+
+    for package in installed.iter() {
+        if package.name == info.name {
+            continue;
+        }
+
+        // replacements only ever match on the real package name, it doesn't
+        // make sense to replace something just because it provides the same
+        // thing that we do
+        if replaces
+            .iter()
+            .any(|r| r.satisfied_by(&package.name, &package.pkgver()))
+        {
+            replaced.push(package);
+            continue;
+        }
+
+        let conflicting = conflicts.iter().any(|c| c.satisfied_by_package(package))
+            || depends::parse_all(&package.conflicts)?
+                .iter()
+                .any(|c| c.satisfied_by_package(info));
+
+        if conflicting {
+            errors.push(format!(
+                "{} conflicts with installed package {}",
+                info.name, package.name
+            ));
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(errors.join("\n").into());
+    }
+
+    Ok(replaced)
+}
+
+fn find_conflicts(
+    root: &Path,
+    allowed: &[&str],
+    files: &[String],
+    owners: &HashMap<String, Vec<String>>,
+) -> Vec<String> {
+    let mut conflicts = Vec::new();
+
+    for file in files.iter() {
+        // directories are shared between packages, we only care about things
+        // that we would actually replace
+        if file.ends_with('/') {
+            continue;
+        }
+
+        let path = root.join(file);
+        match fs::symlink_metadata(&path) {
+            Ok(meta) if meta.is_dir() => continue,
+            Ok(_) => match owners.get(file) {
+                Some(owners) => {
+                    for owner in owners.iter() {
+                        if !allowed.contains(&owner.as_str()) {
+                            conflicts.push(format!("{} (owned by {})", file, owner));
+                        }
+                    }
+                }
+                None => conflicts.push(format!("{} (exists in filesystem)", file)),
+            },
+            Err(_) => continue,
+        }
+    }
+
+    conflicts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::db::LocalDatabase;
+
+    fn test_installed(name: &str, provides: &[&str], conflicts: &[&str]) -> InstalledPackage {
+        InstalledPackage {
+            provides: provides.iter().map(|p| p.to_string()).collect(),
+            conflicts: conflicts.iter().map(|c| c.to_string()).collect(),
+            ..InstalledPackage::for_test(name, "1.0")
+        }
+    }
+
+    fn test_info(name: &str, conflicts: &[&str], replaces: &[&str]) -> PackageInfo {
+        PackageInfo {
+            name: String::from(name),
+            version: String::from("2.0"),
+            release: 1,
+            conflicts: conflicts.iter().map(|c| c.to_string()).collect(),
+            replaces: replaces.iter().map(|r| r.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_conflicts() {
+        let installed = vec![
+            test_installed("gawk", &["awk"], &[]),
+            test_installed("nano", &[], &["vim-minimal"]),
+            test_installed("foo", &[], &[]),
+        ];
+
+        // conflicts through provides and in the other direction
+        assert!(check_conflicts(&test_info("mawk", &["awk"], &[]), &installed).is_err());
+        assert!(check_conflicts(&test_info("vim-minimal", &[], &[]), &installed).is_err());
+        assert!(check_conflicts(&test_info("foo", &["foo"], &[]), &installed).is_ok());
+
+        // replacing something means that we don't conflict with it, but only
+        // its real name counts
+        let replaced =
+            check_conflicts(&test_info("mawk", &["awk"], &["gawk"]), &installed).unwrap();
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(replaced[0].name, "gawk");
+        assert!(
+            check_conflicts(&test_info("bar", &[], &["awk"]), &installed)
+                .unwrap()
+                .is_empty()
+        );
+        assert!(
+            check_conflicts(&test_info("bar", &[], &["foo>1.0"]), &installed)
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    fn test_root(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mpm-install-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Builds a package archive containing the given files, each of which
+    /// just contains its own path.
+    fn build_package(dir: &Path, name: &str, version: &str, files: &[&str]) -> String {
+        let files: Vec<(&str, &str)> = files.iter().map(|f| (*f, *f)).collect();
+        build_package_with(dir, name, version, &files, &[])
+    }
+
+    /// Builds a package archive containing the given files and contents, with
+    /// some of them marked for backup.
+    fn build_package_with(
+        dir: &Path,
+        name: &str,
+        version: &str,
+        files: &[(&str, &str)],
+        backup: &[&str],
+    ) -> String {
+        let pkgdir = dir.join(format!("{}-{}-pkg", name, version));
+        let mut dirs = Vec::new();
+        for (file, contents) in files.iter() {
+            let path = pkgdir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, contents).unwrap();
+
+            let top = file.split('/').next().unwrap();
+            if !dirs.contains(&top) {
+                dirs.push(top);
+            }
+        }
+
+        let mut pkginfo = format!("pkgname = {}\npkgver = {}-1\narch = any\n", name, version);
+        for file in backup.iter() {
+            pkginfo += &format!("backup = {}\n", file);
+        }
+
+        let mtree = Mtree::from_dir(&pkgdir).unwrap();
+        fs::write(pkgdir.join(MTREE_FILE), mtree.to_string()).unwrap();
+        fs::write(pkgdir.join(PKGINFO_FILE), pkginfo).unwrap();
+
+        let archive = dir.join(format!("{}-{}-1-any.pkg.tar.gz", name, version));
+        let status = Exec::cmd("bsdtar")
+            .arg("czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&pkgdir)
+            .arg(PKGINFO_FILE)
+            .arg(MTREE_FILE)
+            .args(&dirs)
+            .join()
+            .unwrap();
+        assert!(status.success());
+
+        archive.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_install_upgrade() {
+        let root = test_root("upgrade");
+        let dbpath = root.join("var/lib/mpm");
+        let old = build_package(&root, "foo", "1.0", &["usr/bin/foo", "usr/share/foo/old"]);
+        let new = build_package(&root, "foo", "1.1", &["usr/bin/foo", "usr/share/foo/new"]);
+
+        let mut txn = Transaction::begin(&root, &dbpath).unwrap();
+        install_package(&mut txn, &old, None).unwrap();
+        txn.commit().unwrap();
+        assert!(root.join("usr/share/foo/old").exists());
+
+        let mut txn = Transaction::begin(&root, &dbpath).unwrap();
+        install_package(&mut txn, &new, None).unwrap();
+        txn.commit().unwrap();
+        assert!(!root.join("usr/share/foo/old").exists());
+        assert!(root.join("usr/share/foo/new").exists());
+
+        let installed = LocalDatabase::open(&dbpath)
+            .unwrap()
+            .get("foo")
+            .unwrap()
+            .unwrap();
+        assert_eq!(installed.version, "1.1");
+        assert!(installed.files.contains(&String::from("usr/share/foo/new")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_merge_backup() {
+        // nothing on disk, or the admin never touched it
+        assert_eq!(merge_backup(Some("a"), None, "b"), Merge::Install);
+        assert_eq!(merge_backup(Some("a"), Some("a"), "b"), Merge::Install);
+
+        // the package didn't change it, or both ended up the same
+        assert_eq!(merge_backup(Some("a"), Some("b"), "a"), Merge::Keep);
+        assert_eq!(merge_backup(Some("a"), Some("b"), "b"), Merge::Keep);
+        assert_eq!(merge_backup(None, Some("b"), "b"), Merge::Keep);
+
+        // everybody changed it, or we don't know what it used to be
+        assert_eq!(merge_backup(Some("a"), Some("b"), "c"), Merge::InstallNew);
+        assert_eq!(merge_backup(None, Some("b"), "c"), Merge::InstallNew);
+    }
+
+    #[test]
+    fn test_install_backup() {
+        let root = test_root("backup");
+        let dbpath = root.join("var/lib/mpm");
+        let backup = [
+            "etc/foo.conf",
+            "etc/foo/kept.conf",
+            "etc/foo/untouched.conf",
+        ];
+        let v1 = build_package_with(
+            &root,
+            "foo",
+            "1.0",
+            &[
+                ("etc/foo.conf", "v1"),
+                ("etc/foo/kept.conf", "v1"),
+                ("etc/foo/untouched.conf", "v1"),
+                ("etc/foo/dropped.conf", "v1"),
+            ],
+            &[
+                "etc/foo.conf",
+                "etc/foo/kept.conf",
+                "etc/foo/untouched.conf",
+                "etc/foo/dropped.conf",
+            ],
+        );
+        let v2 = build_package_with(
+            &root,
+            "foo",
+            "2.0",
+            &[
+                ("etc/foo.conf", "v2"),
+                ("etc/foo/kept.conf", "v1"),
+                ("etc/foo/untouched.conf", "v2"),
+            ],
+            &backup,
+        );
+
+        let mut txn = Transaction::begin(&root, &dbpath).unwrap();
+        install_package(&mut txn, &v1, None).unwrap();
+        txn.commit().unwrap();
+
+        for file in ["etc/foo.conf", "etc/foo/kept.conf", "etc/foo/dropped.conf"] {
+            fs::write(root.join(file), "edited").unwrap();
+        }
+
+        let mut txn = Transaction::begin(&root, &dbpath).unwrap();
+        install_package(&mut txn, &v2, None).unwrap();
+        txn.commit().unwrap();
+
+        let read = |file: &str| fs::read_to_string(root.join(file)).unwrap();
+        assert_eq!(read("etc/foo.conf"), "edited");
+        assert_eq!(read("etc/foo.conf.mpmnew"), "v2");
+        assert_eq!(read("etc/foo/kept.conf"), "edited");
+        assert!(!root.join("etc/foo/kept.conf.mpmnew").exists());
+        assert_eq!(read("etc/foo/untouched.conf"), "v2");
+        assert!(!root.join("etc/foo/dropped.conf").exists());
+        assert_eq!(read("etc/foo/dropped.conf.mpmsave"), "edited");
+
+        // the checksums are always those of the copies that were shipped
+        let installed = LocalDatabase::open(&dbpath)
+            .unwrap()
+            .get("foo")
+            .unwrap()
+            .unwrap();
+        assert_eq!(installed.backup.keys().collect::<Vec<_>>(), backup);
+        assert_eq!(
+            installed.backup["etc/foo/untouched.conf"],
+            downloader::file_sha256sum(root.join("etc/foo/untouched.conf").to_str().unwrap())
+                .unwrap()
+        );
+        assert_ne!(
+            installed.backup["etc/foo.conf"],
+            downloader::file_sha256sum(root.join("etc/foo.conf").to_str().unwrap()).unwrap()
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_install_rollback() {
+        let root = test_root("rollback");
+        let dbpath = root.join("var/lib/mpm");
+        let foo = build_package(&root, "foo", "1.0", &["usr/bin/foo", "usr/share/foo/data"]);
+        let bar = build_package(&root, "bar", "1.0", &["usr/bin/bar", "usr/bin/existing"]);
+
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("usr/bin/existing"), "untracked").unwrap();
+
+        // bar can't be installed over a file that nobody owns, which should
+        // take foo down with it
+        let mut txn = Transaction::begin(&root, &dbpath).unwrap();
+        install_package(&mut txn, &foo, None).unwrap();
+        assert!(root.join("usr/bin/foo").exists());
+        let err = install_package(&mut txn, &bar, None)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("usr/bin/existing (exists in filesystem)"));
+        drop(txn);
+
+        assert!(!root.join("usr/bin/foo").exists());
+        assert!(!root.join("usr/share").exists());
+        assert_eq!(
+            fs::read(root.join("usr/bin/existing")).unwrap(),
+            b"untracked"
+        );
+        assert!(LocalDatabase::open(&dbpath)
+            .unwrap()
+            .packages()
+            .unwrap()
+            .is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
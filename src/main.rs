@@ -1,10 +1,42 @@
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgGroup};
 
+mod clean;
 mod install;
 mod package;
+mod query;
+mod remove;
+mod repo;
+mod sync;
 mod upgrade;
+mod verify;
+mod version;
 
+mod config;
+mod db;
+mod depends;
 mod downloader;
+mod transaction;
+
+#[cfg(test)]
+mod test_server;
+
+fn root_arg<'a>() -> Arg<'a> {
+    Arg::new("root")
+        .long("root")
+        .about("Install into an alternate root directory (defaults to /)")
+        .forbid_empty_values(true)
+        .takes_value(true)
+        .value_name("DIR")
+}
+
+fn dbpath_arg<'a>() -> Arg<'a> {
+    Arg::new("dbpath")
+        .long("dbpath")
+        .about("Use an alternate database directory (defaults to ROOT/var/lib/mpm)")
+        .forbid_empty_values(true)
+        .takes_value(true)
+        .value_name("DIR")
+}
 
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let version = format!(
@@ -20,36 +52,315 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
         .author("Mario Finelli <mario@finel.li>")
         .about("mario's package manager")
         .setting(AppSettings::ArgRequiredElseHelp)
-        .subcommand(App::new("install").about("install a package"))
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .about("Use an alternate config file (defaults to $MPM_CONFIG or /etc/mpm.conf)")
+                .global(true)
+                .forbid_empty_values(true)
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .subcommand(
+            App::new("clean")
+                .about("remove old packages from the cache")
+                .arg(
+                    Arg::new("keep")
+                        .short('k')
+                        .long("keep")
+                        .about("Keep the newest N versions of each package instead of the installed ones")
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("N"),
+                )
+                .arg(
+                    Arg::new("cachedir")
+                        .long("cachedir")
+                        .about("Clean an alternate package cache")
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("DIR"),
+                )
+                .arg(root_arg())
+                .arg(dbpath_arg()),
+        )
+        .subcommand(
+            App::new("install")
+                .about("install a package")
+                .arg(
+                    Arg::new("package")
+                        .about("Package archive(s) to install")
+                        .required(true)
+                        .multiple_values(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("nodeps")
+                        .short('d')
+                        .long("nodeps")
+                        .about("Skip dependency checks"),
+                )
+                .arg(
+                    Arg::new("asdeps")
+                        .long("asdeps")
+                        .about("Mark the packages as installed as dependencies"),
+                )
+                .arg(root_arg())
+                .arg(dbpath_arg()),
+        )
+        .subcommand(
+            App::new("package")
+                .about("build a package")
+                .arg(
+                    Arg::new("recipe")
+                        .short('r')
+                        .long("recipe")
+                        .about(concat!(
+                            "Specify a custom recipe file ",
+                            "(defaults to pkgrecipe.yml)"
+                        ))
+                        .required(false)
+                        .multiple_occurrences(false)
+                        .multiple_values(false)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .default_value("pkgrecipe.yaml"),
+                )
+                .arg(
+                    Arg::new("builddir")
+                        .long("builddir")
+                        .about("Build in an alternate directory (defaults to the current one)")
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("DIR"),
+                )
+                .arg(
+                    Arg::new("sourcedir")
+                        .long("sourcedir")
+                        .about("Keep downloaded sources in an alternate directory")
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("DIR"),
+                ),
+        )
+        .subcommand(
+            App::new("query")
+                .alias("q")
+                .about("query the installed packages")
+                .arg(
+                    Arg::new("package")
+                        .about("Installed package(s) to query (defaults to all of them)")
+                        .multiple_values(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("info")
+                        .short('i')
+                        .long("info")
+                        .about("Show everything known about the packages"),
+                )
+                .arg(
+                    Arg::new("files")
+                        .short('l')
+                        .long("files")
+                        .about("List the files owned by the packages"),
+                )
+                .arg(
+                    Arg::new("owns")
+                        .short('o')
+                        .long("owns")
+                        .about("Find the packages that own the files")
+                        .multiple_values(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .conflicts_with("package"),
+                )
+                .arg(
+                    Arg::new("orphans")
+                        .short('t')
+                        .long("orphans")
+                        .about("List packages installed as dependencies that nothing requires"),
+                )
+                .group(ArgGroup::new("mode").args(&["info", "files", "owns", "orphans"]))
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .about("Print the results as JSON"),
+                )
+                .arg(root_arg())
+                .arg(dbpath_arg()),
+        )
+        .subcommand(
+            App::new("remove")
+                .about("remove installed packages")
+                .arg(
+                    Arg::new("package")
+                        .about("Package(s) to remove")
+                        .required(true)
+                        .multiple_values(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("cascade")
+                        .short('c')
+                        .long("cascade")
+                        .about("Also remove packages that depend on the targets")
+                        .conflicts_with("nodeps"),
+                )
+                .arg(
+                    Arg::new("nodeps")
+                        .short('d')
+                        .long("nodeps")
+                        .about("Skip checking whether other packages depend on the targets"),
+                )
+                .arg(root_arg())
+                .arg(dbpath_arg()),
+        )
+        .subcommand(
+            App::new("repo-add")
+                .about("add packages to a repository database")
+                .arg(
+                    Arg::new("database")
+                        .about("Repository database to update (created if missing)")
+                        .required(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("DB")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("package")
+                        .about("Package archive(s) to add")
+                        .required(true)
+                        .multiple_values(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("FILE")
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            App::new("repo-remove")
+                .about("remove packages from a repository database")
+                .arg(
+                    Arg::new("database")
+                        .about("Repository database to update")
+                        .required(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("DB")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("package")
+                        .about("Package(s) to remove")
+                        .required(true)
+                        .multiple_values(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .index(2),
+                ),
+        )
+        .subcommand(
+            App::new("verify")
+                .about("check installed files against their package manifests")
+                .arg(
+                    Arg::new("package")
+                        .about("Installed package(s) to check (defaults to all of them)")
+                        .multiple_values(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .index(1),
+                )
+                .arg(root_arg())
+                .arg(dbpath_arg()),
+        )
+        .subcommand(
+            App::new("vercmp")
+                .about("compare two package versions")
+                .arg(
+                    Arg::new("a")
+                        .about("First version")
+                        .required(true)
+                        .value_name("VERSION")
+                        .index(1),
+                )
+                .arg(
+                    Arg::new("b")
+                        .about("Second version")
+                        .required(true)
+                        .value_name("VERSION")
+                        .index(2),
+                ),
+        )
         .subcommand(
-            App::new("package").about("build a package").arg(
-                Arg::new("recipe")
-                    .short('r')
-                    .long("recipe")
-                    .about(concat!(
-                        "Specify a custom recipe file ",
-                        "(defaults to pkgrecipe.yml)"
-                    ))
-                    .required(false)
-                    .multiple_occurrences(false)
-                    .multiple_values(false)
-                    .forbid_empty_values(true)
-                    .takes_value(true)
-                    .value_name("FILE")
-                    .default_value("pkgrecipe.yaml"),
-            ),
+            App::new("sync")
+                .about("download the latest repository databases")
+                .arg(
+                    Arg::new("force")
+                        .short('f')
+                        .long("force")
+                        .about("Download the databases even if they appear to be up to date"),
+                )
+                .arg(root_arg())
+                .arg(dbpath_arg()),
         )
         .subcommand(
             App::new("upgrade")
                 .aliases(&["up", ""])
-                .about("upgrade all installed packages"),
+                .about("upgrade all installed packages")
+                .arg(
+                    Arg::new("noconfirm")
+                        .long("noconfirm")
+                        .about("Don't ask for confirmation before upgrading"),
+                )
+                .arg(
+                    Arg::new("ignore")
+                        .long("ignore")
+                        .about("Don't upgrade the package, in addition to those in the config")
+                        .multiple_occurrences(true)
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("NAME"),
+                )
+                .arg(
+                    Arg::new("cachedir")
+                        .long("cachedir")
+                        .about("Download packages into an alternate directory")
+                        .forbid_empty_values(true)
+                        .takes_value(true)
+                        .value_name("DIR"),
+                )
+                .arg(root_arg())
+                .arg(dbpath_arg()),
         )
         .get_matches();
 
     match cli.subcommand() {
-        Some(("install", _install_matches)) => install::run(),
+        Some(("clean", clean_matches)) => clean::run(clean_matches),
+        Some(("install", install_matches)) => install::run(install_matches),
         Some(("package", package_matches)) => package::run(package_matches).await,
-        Some(("upgrade", _upgrade_matches)) => upgrade::run(),
+        Some(("query", query_matches)) => query::run(query_matches),
+        Some(("remove", remove_matches)) => remove::run(remove_matches),
+        Some(("repo-add", repo_matches)) => repo::run_add(repo_matches),
+        Some(("repo-remove", repo_matches)) => repo::run_remove(repo_matches),
+        Some(("sync", sync_matches)) => sync::run(sync_matches).await,
+        Some(("upgrade", upgrade_matches)) => upgrade::run(upgrade_matches).await,
+        Some(("verify", verify_matches)) => verify::run(verify_matches),
+        Some(("vercmp", vercmp_matches)) => version::run(vercmp_matches),
         _ => unreachable!(),
     }
 }
@@ -59,7 +370,7 @@ async fn main() {
     std::process::exit(match run().await {
         Ok(_) => 0,
         Err(err) => {
-            eprintln!("error: {:?}", err);
+            eprintln!("error: {}", err);
             1
         }
     });
@@ -3,11 +3,15 @@ use std::fs;
 use std::path::Path;
 
 use clap::ArgMatches;
-use reqwest::Client;
 
 pub mod bash;
+pub mod mtree;
+pub mod pkginfo;
 pub mod recipe;
+pub mod scriptlet;
+pub mod vcs;
 
+use super::config::Config;
 use super::downloader;
 use recipe::PackageRecipe;
 
@@ -15,23 +19,30 @@ static SRCDIR_BASE: &str = "tmpsrc";
 static PKGDIR_BASE: &str = "tmppkg";
 
 pub async fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
-    let recipe_file = match cli.value_of("recipe") {
-        Some(file) => file,
-        None => "pkgrecipe.yaml",
+    let config = Config::load(cli)?;
+    let recipe_file = cli.value_of("recipe").unwrap_or("pkgrecipe.yaml");
+    let mut recipe = PackageRecipe::from_file(recipe_file)?;
+
+    let builddir = match cli.value_of("builddir") {
+        Some(dir) => Path::new(dir).to_path_buf(),
+        None => config.build_dir.clone(),
     };
-    let recipe = PackageRecipe::from_file(recipe_file)?;
+    let srcdir = builddir.join(SRCDIR_BASE).to_str().unwrap().to_string();
+    let pkgdir = builddir.join(PKGDIR_BASE).to_str().unwrap().to_string();
 
     // TODO: abort if the package is already built
 
-    let client = Client::builder().build().unwrap();
-    recipe.download_sources(&client).await;
-    match recipe.verify_sources() {
+    let srcdest = config.source_dir(cli);
+    fs::create_dir_all(&srcdest)?;
+    let client = config.client()?;
+    recipe.download_sources(&client, &srcdest, &config).await?;
+    match recipe.verify_sources(&srcdest) {
         Ok(_) => (),
         Err(err) => return Err(err),
     }
 
     // cleanup any existing packaging artifacts
-    let packaging_dirs = [SRCDIR_BASE, PKGDIR_BASE];
+    let packaging_dirs = [&srcdir, &pkgdir];
     for dir in packaging_dirs {
         if Path::new(dir).exists() {
             match fs::remove_dir_all(dir) {
@@ -43,64 +54,80 @@ pub async fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
 
     // setup packaging directories
     for dir in packaging_dirs {
-        match fs::create_dir(dir) {
+        match fs::create_dir_all(dir) {
             Ok(_) => (),
             Err(err) => return Err(Box::new(err)),
         }
     }
 
-    match recipe.symlink_sources(SRCDIR_BASE) {
+    match recipe.symlink_sources(&srcdest, &srcdir) {
         Ok(_) => (),
         Err(err) => return Err(err),
     }
 
-    let extracted_sources = match recipe.extract_sources(SRCDIR_BASE) {
+    let extracted_sources = match recipe.extract_sources(&srcdir) {
         Ok(sources) => sources,
         Err(err) => return Err(err),
     };
 
+    let full_srcdir = std::fs::canonicalize(&srcdir)
+        .unwrap()
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    if let Some(pkgver) = recipe.pkgver.clone() {
+        let mut vars = HashMap::new();
+        vars.insert("pkgname", recipe.name());
+        vars.insert("srcdir", &full_srcdir);
+
+        let version = match bash::run_script_output(&srcdir, &pkgver, &vars) {
+            Some(version) => version,
+            None => return Err("pkgver failed")?,
+        };
+        recipe.set_version(&version)?;
+        println!("building version {}", recipe.full_version());
+    }
+
     let mut vars = HashMap::new();
     vars.insert("pkgname", recipe.name());
     vars.insert("pkgver", recipe.version());
 
     let mut vars_with_srcdir = vars.clone();
-    let full_srcdir = std::fs::canonicalize(SRCDIR_BASE).unwrap().to_str().unwrap().to_string();
     vars_with_srcdir.insert("srcdir", &full_srcdir);
 
     if let Some(ref source) = recipe.source {
-        let status = bash::run_script(SRCDIR_BASE, &source, &vars_with_srcdir);
+        let status = bash::run_script(&srcdir, source, &vars_with_srcdir);
         if !status {
             return Err("source failed")?;
         }
     }
 
-    match recipe.create_source_package(SRCDIR_BASE, recipe_file, extracted_sources) {
+    match recipe.create_source_package(&srcdir, &srcdest, recipe_file, extracted_sources) {
         Ok(status) => {
-            if ! status {
+            if !status {
                 return Err("failed to create source package")?;
             }
-
-            ()
-        },
+        }
         Err(err) => return Err(err),
     }
 
     if let Some(ref prepare) = recipe.prepare {
-        let status = bash::run_script(SRCDIR_BASE, &prepare, &vars_with_srcdir);
+        let status = bash::run_script(&srcdir, prepare, &vars_with_srcdir);
         if !status {
             return Err("prepare failed")?;
         }
     }
 
     if let Some(ref build) = recipe.build {
-        let status = bash::run_script(SRCDIR_BASE, &build, &vars_with_srcdir);
+        let status = bash::run_script(&srcdir, build, &vars_with_srcdir);
         if !status {
             return Err("build failed")?;
         }
     }
 
     if let Some(ref check) = recipe.check {
-        let status = bash::run_script(SRCDIR_BASE, &check, &vars_with_srcdir);
+        let status = bash::run_script(&srcdir, check, &vars_with_srcdir);
         if !status {
             return Err("check failed")?;
         }
@@ -108,17 +135,21 @@ pub async fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
 
     if let Some(ref packages) = recipe.packages {
         for package in packages.iter() {
-            match fs::create_dir(Path::new(PKGDIR_BASE).join(package.name())) {
+            match fs::create_dir(Path::new(&pkgdir).join(package.name())) {
                 Ok(_) => (),
                 Err(err) => return Err(Box::new(err)),
             }
 
             let mut vars_with_pkgdir = vars_with_srcdir.clone();
-            let full_pkgdir = std::fs::canonicalize(Path::new(PKGDIR_BASE).join(package.name())).unwrap().to_str().unwrap().to_string();
+            let full_pkgdir = std::fs::canonicalize(Path::new(&pkgdir).join(package.name()))
+                .unwrap()
+                .to_str()
+                .unwrap()
+                .to_string();
             vars_with_pkgdir.insert("pkgdir", &full_pkgdir);
 
             if let Some(p) = &package.package() {
-                let status = bash::run_script(SRCDIR_BASE, p, &vars_with_pkgdir);
+                let status = bash::run_script(&srcdir, p, &vars_with_pkgdir);
 
                 if !status {
                     return Err(format!("package {} failed", package.name()))?;
@@ -126,7 +157,9 @@ pub async fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
             }
 
             package.create_debug_package();
-            package.create_package(&recipe, &full_pkgdir);
+            if !package.create_package(&recipe, &full_pkgdir, &config)? {
+                return Err(format!("failed to create package {}", package.name()))?;
+            }
         }
     }
 
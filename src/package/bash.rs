@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::path::Path;
 
 use subprocess::{Exec, Redirection};
 
@@ -14,6 +15,53 @@ pub fn run_script(cwd: &str, script: &str, variables: &HashMap<&str, &String>) -
     run.success()
 }
 
+/// Runs the script and returns what it printed, or `None` if it failed.
+/// Anything printed to stderr is passed on to the user.
+pub fn run_script_output(
+    cwd: &str,
+    script: &str,
+    variables: &HashMap<&str, &String>,
+) -> Option<String> {
+    let run = Exec::cmd("bash")
+        .cwd(cwd)
+        .env_clear()
+        .stdin(create_script(script, variables).as_str())
+        .stdout(Redirection::Pipe)
+        .capture()
+        .ok()?;
+
+    if !run.success() {
+        return None;
+    }
+
+    Some(run.stdout_str().trim().to_string())
+}
+
+/// Runs the script on the system installed in `root`, chrooting into it unless
+/// it's the real root, and passes its output on to the user.
+pub fn run_script_in_root(root: &Path, script: &str, variables: &HashMap<&str, &String>) -> bool {
+    let bash = if root == Path::new("/") {
+        Exec::cmd("bash")
+    } else {
+        Exec::cmd("chroot").arg(root).arg("bash")
+    };
+
+    let run = bash
+        .cwd(root)
+        .env_clear()
+        .stdin(create_script(script, variables).as_str())
+        .stderr(Redirection::Merge)
+        .capture();
+
+    match run {
+        Ok(capture) => {
+            print!("{}", capture.stdout_str());
+            capture.success()
+        }
+        Err(_) => false,
+    }
+}
+
 fn create_script(script: &str, variables: &HashMap<&str, &String>) -> String {
     let mut bash = "set -ex\n\n".to_string();
 
@@ -36,8 +84,20 @@ mod tests {
         let mut vars = HashMap::new();
         vars.insert("somevar", &somevar);
         assert_eq!(
-            create_script(&script, &vars),
+            create_script(script, &vars),
             "set -ex\n\nsomevar='testing'\n\necho $somevar\n\nexit 0\n"
         );
     }
+
+    #[test]
+    fn test_run_script_output() {
+        let somevar = String::from("1.2");
+        let mut vars = HashMap::new();
+        vars.insert("somevar", &somevar);
+        assert_eq!(
+            run_script_output("/", "echo \"  v$somevar \"", &vars),
+            Some(String::from("v1.2"))
+        );
+        assert_eq!(run_script_output("/", "false", &vars), None);
+    }
 }
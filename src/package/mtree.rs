@@ -0,0 +1,385 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use subprocess::{Exec, Redirection};
+
+use super::downloader;
+
+/// Name of the file manifest stored at the root of every package archive.
+pub static MTREE_FILE: &str = ".MTREE";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EntryType {
+    File,
+    Dir,
+    Link,
+}
+
+/// A single file in the package as described by an mtree(5)-style line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MtreeEntry {
+    pub path: String,
+    pub kind: EntryType,
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: Option<u64>,
+    pub link: Option<String>,
+    pub sha256: Option<String>,
+}
+
+/// The manifest of every file contained in a package, written into the
+/// archive as `.MTREE` so that installed files can be checked later.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Mtree {
+    pub entries: Vec<MtreeEntry>,
+}
+
+impl Mtree {
+    /// Builds the manifest for everything in `path` except for the package
+    /// metadata files at its root.
+    pub fn from_dir(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut mtree = Mtree::default();
+        mtree.add_dir(path, path)?;
+        Ok(mtree)
+    }
+
+    /// Builds the manifest for a package that's about to be archived. The
+    /// archive is created under fakeroot, so the owners that its files will
+    /// have are the ones that fakeroot reports rather than those of whoever
+    /// built the package.
+    pub fn from_pkgdir(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut mtree = Self::from_dir(path)?;
+
+        let top: Vec<&str> = mtree
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .filter(|path| !path.contains('/'))
+            .collect();
+        if top.is_empty() {
+            return Ok(mtree);
+        }
+
+        let capture = Exec::cmd("fakeroot")
+            .arg("--")
+            .arg("bsdtar")
+            .arg("-cf")
+            .arg("-")
+            .arg("--format=mtree")
+            .arg("--options=!all,type,uid,gid")
+            .arg("-C")
+            .arg(path)
+            .args(&top)
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Pipe)
+            .capture()?;
+        if !capture.success() {
+            return Err(format!(
+                "unable to list owners in {}: {}",
+                path.display(),
+                capture.stderr_str().trim()
+            ))?;
+        }
+
+        let owners: HashMap<String, (u32, u32)> = Self::parse(&capture.stdout_str())?
+            .entries
+            .into_iter()
+            .map(|entry| (entry.path, (entry.uid, entry.gid)))
+            .collect();
+        for entry in mtree.entries.iter_mut() {
+            if let Some((uid, gid)) = owners.get(&entry.path) {
+                entry.uid = *uid;
+                entry.gid = *gid;
+            }
+        }
+
+        Ok(mtree)
+    }
+
+    pub fn from_archive(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut source = File::open(path)?;
+        let mut contents = Vec::new();
+
+        match compress_tools::uncompress_archive_file(&mut source, &mut contents, MTREE_FILE) {
+            Ok(_) => (),
+            Err(err) => return Err(format!("unable to read manifest from {}: {}", path, err))?,
+        }
+
+        Self::parse(&String::from_utf8(contents)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut mtree = Mtree::default();
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let path = unescape(fields.next().unwrap());
+            let path = path.strip_prefix("./").unwrap_or(&path).to_string();
+
+            let mut entry = MtreeEntry {
+                path,
+                kind: EntryType::File,
+                mode: 0,
+                uid: 0,
+                gid: 0,
+                size: None,
+                link: None,
+                sha256: None,
+            };
+
+            for field in fields {
+                let (key, value) = match field.split_once('=') {
+                    Some(kv) => kv,
+                    None => return Err(format!("invalid manifest line: {}", line))?,
+                };
+
+                match key {
+                    "type" => {
+                        entry.kind = match value {
+                            "file" => EntryType::File,
+                            "dir" => EntryType::Dir,
+                            "link" => EntryType::Link,
+                            _ => return Err(format!("unknown file type {}", value))?,
+                        }
+                    }
+                    "mode" => entry.mode = u32::from_str_radix(value, 8)?,
+                    "uid" => entry.uid = value.parse()?,
+                    "gid" => entry.gid = value.parse()?,
+                    "size" => entry.size = Some(value.parse()?),
+                    "link" => entry.link = Some(unescape(value)),
+                    "sha256digest" => entry.sha256 = Some(value.to_string()),
+                    _ => continue,
+                }
+            }
+
+            mtree.entries.push(entry);
+        }
+
+        Ok(mtree)
+    }
+
+    fn add_dir(&mut self, root: &Path, dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut entries = fs::read_dir(dir)?
+            .map(|res| res.map(|e| e.path()))
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        entries.sort();
+
+        for path in entries.iter() {
+            let relative = path.strip_prefix(root).unwrap().to_str().unwrap();
+            if dir == root && relative.starts_with('.') {
+                continue;
+            }
+
+            let meta = path.symlink_metadata()?;
+
+            let mut entry = MtreeEntry {
+                path: relative.to_string(),
+                kind: EntryType::File,
+                mode: meta.permissions().mode() & 0o7777,
+                uid: meta.uid(),
+                gid: meta.gid(),
+                size: None,
+                link: None,
+                sha256: None,
+            };
+
+            if meta.file_type().is_symlink() {
+                entry.kind = EntryType::Link;
+                entry.link = Some(fs::read_link(path)?.to_str().unwrap().to_string());
+                self.entries.push(entry);
+            } else if meta.is_dir() {
+                entry.kind = EntryType::Dir;
+                self.entries.push(entry);
+                self.add_dir(root, path)?;
+            } else {
+                entry.size = Some(meta.len());
+                entry.sha256 = Some(downloader::file_sha256sum(path.to_str().unwrap())?);
+                self.entries.push(entry);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Mtree {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "#mtree")?;
+
+        for entry in self.entries.iter() {
+            let kind = match entry.kind {
+                EntryType::File => "file",
+                EntryType::Dir => "dir",
+                EntryType::Link => "link",
+            };
+
+            write!(
+                f,
+                "./{} type={} mode={:o} uid={} gid={}",
+                escape(&entry.path),
+                kind,
+                entry.mode,
+                entry.uid,
+                entry.gid
+            )?;
+
+            if let Some(size) = entry.size {
+                write!(f, " size={}", size)?;
+            }
+            if let Some(link) = &entry.link {
+                write!(f, " link={}", escape(link))?;
+            }
+            if let Some(sha256) = &entry.sha256 {
+                write!(f, " sha256digest={}", sha256)?;
+            }
+
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Escapes whitespace and other special characters as `\ooo` octal
+/// sequences the way mtree(5) expects.
+fn escape(path: &str) -> String {
+    let mut escaped = String::new();
+
+    for byte in path.bytes() {
+        if byte <= b' ' || byte >= 0x7f || byte == b'\\' || byte == b'#' || byte == b'=' {
+            escaped += &format!("\\{:03o}", byte);
+        } else {
+            escaped.push(byte as char);
+        }
+    }
+
+    escaped
+}
+
+fn unescape(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut unescaped = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() {
+            let octal = std::str::from_utf8(&bytes[i + 1..i + 4]).unwrap_or("");
+            if let Ok(byte) = u8::from_str_radix(octal, 8) {
+                unescaped.push(byte);
+                i += 4;
+                continue;
+            }
+        }
+
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mtree = Mtree {
+            entries: vec![
+                MtreeEntry {
+                    path: String::from("usr"),
+                    kind: EntryType::Dir,
+                    mode: 0o755,
+                    uid: 0,
+                    gid: 0,
+                    size: None,
+                    link: None,
+                    sha256: None,
+                },
+                MtreeEntry {
+                    path: String::from("usr/share/my file"),
+                    kind: EntryType::File,
+                    mode: 0o644,
+                    uid: 1000,
+                    gid: 100,
+                    size: Some(3),
+                    link: None,
+                    sha256: Some(String::from("abc123")),
+                },
+                MtreeEntry {
+                    path: String::from("usr/lib/libfoo.so"),
+                    kind: EntryType::Link,
+                    mode: 0o777,
+                    uid: 0,
+                    gid: 0,
+                    size: None,
+                    link: Some(String::from("libfoo.so.1")),
+                    sha256: None,
+                },
+            ],
+        };
+
+        let output = mtree.to_string();
+        assert!(output.contains("./usr/share/my\\040file type=file mode=644 uid=1000 gid=100"));
+        assert_eq!(Mtree::parse(&output).unwrap(), mtree);
+
+        // manifests without owners have everything owned by root
+        let old = Mtree::parse("./usr type=dir mode=755\n").unwrap();
+        assert_eq!(old.entries[0], mtree.entries[0]);
+    }
+
+    #[test]
+    fn test_from_dir() {
+        let mtree = Mtree::from_dir(Path::new("tests/fixtures")).unwrap();
+        assert_eq!(mtree.entries.len(), 1);
+        assert_eq!(mtree.entries[0].path, "src.tar.gz");
+        assert_eq!(
+            mtree.entries[0].sha256.as_ref().unwrap(),
+            "b6492e004ca58d23bb38e9ea50dab9698edb49b759777143a9105fca58597125"
+        );
+    }
+
+    #[test]
+    fn test_from_pkgdir() {
+        let dir = std::env::temp_dir().join(format!("mpm-mtree-pkgdir-{}", std::process::id()));
+        fs::create_dir_all(dir.join("usr/bin")).unwrap();
+        fs::write(dir.join("usr/bin/foo"), "foo").unwrap();
+        fs::write(dir.join(".PKGINFO"), "pkgname = foo\n").unwrap();
+
+        // only root can give a file away, which is what a build run without
+        // fakeroot would leave behind
+        let _ = std::os::unix::fs::chown(dir.join("usr/bin/foo"), Some(1234), Some(1234));
+        let meta = fs::metadata(dir.join("usr/bin/foo")).unwrap();
+        let on_disk = Mtree::from_dir(&dir).unwrap();
+        assert_eq!(on_disk.entries[2].path, "usr/bin/foo");
+        assert_eq!(
+            (on_disk.entries[2].uid, on_disk.entries[2].gid),
+            (meta.uid(), meta.gid())
+        );
+
+        // but everything in the archive is owned by root
+        let mtree = Mtree::from_pkgdir(&dir).unwrap();
+        assert_eq!(mtree.entries.len(), 3);
+        for entry in mtree.entries.iter() {
+            assert_eq!((entry.uid, entry.gid), (0, 0), "{}", entry.path);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a b\\c"), "a\\040b\\134c");
+        assert_eq!(unescape("a\\040b\\134c"), "a b\\c");
+        assert_eq!(unescape("trailing\\"), "trailing\\");
+    }
+}
@@ -0,0 +1,266 @@
+use std::fs::File;
+
+use super::super::version::Version;
+
+/// Name of the metadata file stored at the root of every package archive.
+pub static PKGINFO_FILE: &str = ".PKGINFO";
+
+/// The metadata describing a built package, written into the archive as
+/// `.PKGINFO` so that it can be installed without the original recipe.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackageInfo {
+    pub name: String,
+    pub base: String,
+    pub epoch: Option<u32>,
+    pub version: String,
+    pub release: u32,
+    pub description: Option<String>,
+    pub url: Option<String>,
+    pub arch: String,
+    pub packager: Option<String>,
+    pub builddate: u64,
+    pub size: u64,
+    pub license: Vec<String>,
+    pub depends: Vec<String>,
+    pub makedepends: Vec<String>,
+    pub checkdepends: Vec<String>,
+    pub provides: Vec<String>,
+    pub conflicts: Vec<String>,
+    pub replaces: Vec<String>,
+    pub backup: Vec<String>,
+}
+
+impl PackageInfo {
+    pub fn from_archive(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut source = File::open(path)?;
+        let mut contents = Vec::new();
+
+        match compress_tools::uncompress_archive_file(&mut source, &mut contents, PKGINFO_FILE) {
+            Ok(_) => (),
+            Err(err) => return Err(format!("unable to read metadata from {}: {}", path, err))?,
+        }
+
+        Self::parse(&String::from_utf8(contents)?)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut info = PackageInfo::default();
+        let mut pkgver = None;
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // values can be empty, which leaves nothing after the `=` once
+            // the line is trimmed
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim().to_string()),
+                None => return Err(format!("invalid metadata line: {}", line))?,
+            };
+
+            match key {
+                "pkgname" => info.name = value,
+                "pkgbase" => info.base = value,
+                "pkgver" => pkgver = Some(value),
+                "pkgdesc" => info.description = Some(value),
+                "url" => info.url = Some(value),
+                "arch" => info.arch = value,
+                "packager" => info.packager = Some(value),
+                "builddate" => info.builddate = value.parse()?,
+                "size" => info.size = value.parse()?,
+                "license" => info.license.push(value),
+                "depend" => info.depends.push(value),
+                "makedepend" => info.makedepends.push(value),
+                "checkdepend" => info.checkdepends.push(value),
+                "provides" => info.provides.push(value),
+                "conflict" => info.conflicts.push(value),
+                "replaces" => info.replaces.push(value),
+                "backup" => info.backup.push(value),
+                // ignore anything we don't know about so that older versions
+                // can still install packages built by newer ones
+                _ => continue,
+            }
+        }
+
+        if info.name.is_empty() {
+            return Err("metadata is missing pkgname".into());
+        }
+
+        match pkgver {
+            Some(pkgver) => {
+                let (epoch, version, release) = split_version(&pkgver)?;
+                info.epoch = epoch;
+                info.version = version;
+                info.release = release;
+            }
+            None => return Err("metadata is missing pkgver")?,
+        }
+
+        Ok(info)
+    }
+
+    pub fn full_version(&self) -> String {
+        if let Some(epoch) = self.epoch {
+            format!("{}:{}-{}", epoch, self.version, self.release)
+        } else {
+            format!("{}-{}", self.version, self.release)
+        }
+    }
+
+    pub fn pkgver(&self) -> Version {
+        Version::new(self.epoch, &self.version, self.release)
+    }
+}
+
+impl std::fmt::Display for PackageInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "# Generated by mpm")?;
+        writeln!(f, "pkgname = {}", self.name)?;
+        writeln!(f, "pkgbase = {}", self.base)?;
+        writeln!(f, "pkgver = {}", self.full_version())?;
+        if let Some(description) = &self.description {
+            writeln!(f, "pkgdesc = {}", description)?;
+        }
+        if let Some(url) = &self.url {
+            writeln!(f, "url = {}", url)?;
+        }
+        writeln!(f, "arch = {}", self.arch)?;
+        if let Some(packager) = &self.packager {
+            writeln!(f, "packager = {}", packager)?;
+        }
+        writeln!(f, "builddate = {}", self.builddate)?;
+        writeln!(f, "size = {}", self.size)?;
+
+        for license in self.license.iter() {
+            writeln!(f, "license = {}", license)?;
+        }
+        for depend in self.depends.iter() {
+            writeln!(f, "depend = {}", depend)?;
+        }
+        for depend in self.makedepends.iter() {
+            writeln!(f, "makedepend = {}", depend)?;
+        }
+        for depend in self.checkdepends.iter() {
+            writeln!(f, "checkdepend = {}", depend)?;
+        }
+        for provides in self.provides.iter() {
+            writeln!(f, "provides = {}", provides)?;
+        }
+        for conflict in self.conflicts.iter() {
+            writeln!(f, "conflict = {}", conflict)?;
+        }
+        for replaces in self.replaces.iter() {
+            writeln!(f, "replaces = {}", replaces)?;
+        }
+        for backup in self.backup.iter() {
+            writeln!(f, "backup = {}", backup)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Splits a full version string (`[epoch:]version-release`) into its parts.
+pub fn split_version(
+    full_version: &str,
+) -> Result<(Option<u32>, String, u32), Box<dyn std::error::Error>> {
+    let (rest, release) = match full_version.rsplit_once('-') {
+        Some((rest, release)) => match release.parse() {
+            Ok(release) => (rest, release),
+            Err(_) => return Err(format!("invalid release in {}", full_version))?,
+        },
+        None => return Err(format!("missing release in {}", full_version))?,
+    };
+
+    let (epoch, version) = match rest.split_once(':') {
+        Some((epoch, version)) => match epoch.parse() {
+            Ok(epoch) => (Some(epoch), version),
+            Err(_) => return Err(format!("invalid epoch in {}", full_version))?,
+        },
+        None => (None, rest),
+    };
+
+    if version.is_empty() {
+        return Err(format!("missing version in {}", full_version).into());
+    }
+
+    Ok((epoch, version.to_string(), release))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_info() -> PackageInfo {
+        PackageInfo {
+            name: String::from("foo-libs"),
+            base: String::from("foo"),
+            epoch: Some(1),
+            version: String::from("2.0"),
+            release: 3,
+            description: Some(String::from("libraries for foo")),
+            url: Some(String::from("https://example.com")),
+            arch: String::from("x86_64"),
+            packager: Some(String::from("Foo Maintainer <foo@example.com>")),
+            builddate: 1638316800,
+            size: 4096,
+            license: vec![String::from("MIT"), String::from("Apache-2.0")],
+            depends: vec![String::from("bar"), String::from("baz")],
+            makedepends: vec![String::from("gcc")],
+            checkdepends: Vec::new(),
+            provides: vec![String::from("libfoo.so=2")],
+            conflicts: vec![String::from("foo-libs-git")],
+            replaces: vec![String::from("libfoo")],
+            backup: vec![String::from("etc/foo.conf")],
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let info = test_info();
+        assert_eq!(PackageInfo::parse(&info.to_string()).unwrap(), info);
+    }
+
+    #[test]
+    fn test_output() {
+        let output = test_info().to_string();
+        assert!(output.contains("\npkgver = 1:2.0-3\n"));
+        assert!(output.contains("\nlicense = MIT\nlicense = Apache-2.0\n"));
+        assert!(!output.contains("checkdepend"));
+        assert!(output.contains("\nprovides = libfoo.so=2\n"));
+        assert!(output.contains("\nconflict = foo-libs-git\n"));
+        assert!(output.contains("\nbackup = etc/foo.conf\n"));
+    }
+
+    #[test]
+    fn test_parse_empty_value() {
+        let info =
+            PackageInfo::parse("pkgname = foo\npkgver = 1.0-1\npkgdesc = \ndepend = bar>=2\n")
+                .unwrap();
+        assert_eq!(info.description.as_deref(), Some(""));
+        assert_eq!(info.depends, vec!["bar>=2"]);
+    }
+
+    #[test]
+    fn test_parse_missing_fields() {
+        assert!(PackageInfo::parse("pkgname = foo\n").is_err());
+        assert!(PackageInfo::parse("pkgver = 1.0-1\n").is_err());
+        assert!(PackageInfo::parse("pkgname foo\n").is_err());
+    }
+
+    #[test]
+    fn test_split_version() {
+        assert_eq!(
+            split_version("1.0-1").unwrap(),
+            (None, String::from("1.0"), 1)
+        );
+        assert_eq!(
+            split_version("2:1.0rc1-3").unwrap(),
+            (Some(2), String::from("1.0rc1"), 3)
+        );
+        assert!(split_version("1.0").is_err());
+        assert!(split_version("a:1.0-1").is_err());
+    }
+}
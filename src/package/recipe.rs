@@ -1,14 +1,22 @@
 use std::env;
 use std::fs::File;
 use std::os::unix::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
-use serde_yaml;
+use serde::Deserialize;
 use subprocess::{Exec, NullFile, Redirection};
+use url::Url;
 
-use super::downloader;
+use super::super::config::{Compression, Config};
+use super::super::depends;
+use super::super::version::Version;
+use super::downloader::{self, Download};
+use super::mtree::{Mtree, MTREE_FILE};
+use super::pkginfo::{PackageInfo, PKGINFO_FILE};
+use super::scriptlet::{Scriptlets, INSTALL_FILE};
+use super::vcs::GitSource;
 
 #[derive(Debug, Deserialize)]
 pub struct PackageRecipe {
@@ -23,17 +31,28 @@ pub struct PackageRecipe {
     depends: Option<Vec<String>>,
     makedepends: Option<Vec<String>>,
     checkdepends: Option<Vec<String>>,
+    provides: Option<Vec<String>>,
+    conflicts: Option<Vec<String>>,
+    replaces: Option<Vec<String>>,
     sources: Option<Vec<PackageRecipeSource>>,
     pub source: Option<String>,
     pub prepare: Option<String>,
     pub build: Option<String>,
     pub check: Option<String>,
+    /// Prints the version to build, for when it comes from the sources, like
+    /// a git checkout, instead of being written in the recipe.
+    pub pkgver: Option<String>,
     pub packages: Option<Vec<PackageRecipePackage>>,
+    /// Where the recipe is, which local sources are relative to.
+    #[serde(skip)]
+    dir: PathBuf,
 }
 
 #[derive(Debug, Deserialize)]
 struct PackageRecipeSource {
     url: String,
+    /// Other places to download the source from, tried in order after `url`.
+    mirrors: Option<Vec<String>>,
     filename: Option<String>,
     sha256sum: Option<String>,
 }
@@ -42,7 +61,13 @@ struct PackageRecipeSource {
 pub struct PackageRecipePackage {
     name: String,
     description: Option<String>,
+    provides: Option<Vec<String>>,
+    conflicts: Option<Vec<String>>,
+    replaces: Option<Vec<String>>,
+    backup: Option<Vec<String>>,
     package: Option<String>,
+    #[serde(flatten)]
+    scriptlets: Scriptlets,
 }
 
 impl PackageRecipe {
@@ -50,8 +75,14 @@ impl PackageRecipe {
         let file = File::open(path).unwrap();
         let mut data: PackageRecipe = serde_yaml::from_reader(file).unwrap();
 
+        data.dir = match Path::new(path).parent() {
+            Some(dir) if dir != Path::new("") => std::fs::canonicalize(dir)?,
+            _ => env::current_dir()?,
+        };
         data.variable_substitution();
+        data.validate_sources()?;
         data.compute_filenames();
+        data.validate_depends()?;
 
         // println!("{:#?}", data);
         Ok(data)
@@ -65,11 +96,35 @@ impl PackageRecipe {
         &self.version
     }
 
+    pub fn pkgver(&self) -> Version {
+        Version::new(self.epoch, &self.version, self.release)
+    }
+
+    pub fn full_version(&self) -> String {
+        self.pkgver().to_string()
+    }
+
+    /// Replaces the version from the recipe with one from the `pkgver` script.
+    pub fn set_version(&mut self, version: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let invalid = |c: char| c.is_whitespace() || c == '-' || c == ':' || c == '/';
+        if version.is_empty() || version.contains(invalid) {
+            return Err(format!("invalid version {:?}", version).into());
+        }
+
+        self.version = version.to_string();
+        Ok(())
+    }
+
     pub fn package_basename(&self) -> String {
-        if let Some(epoch) = self.epoch {
-            format!("{}-{}:{}-{}", self.name, epoch, self.version, self.release)
-        } else {
-            format!("{}-{}-{}", self.name, self.version, self.release)
+        format!("{}-{}", self.name, self.full_version())
+    }
+
+    pub fn package_arch(&self) -> &str {
+        // packages that don't contain any compiled code can declare that they
+        // work everywhere, otherwise we build for whatever we're running on
+        match &self.arch {
+            Some(arch) if arch.iter().any(|a| a == "any") => "any",
+            _ => env::consts::ARCH,
         }
     }
 
@@ -79,63 +134,143 @@ impl PackageRecipe {
                 source.variable_substitution("pkgname", &self.name);
                 source.variable_substitution("pkgver", &self.version);
                 if let Some(url) = &self.url {
-                    source.variable_substitution("url", &url);
+                    source.variable_substitution("url", url);
                 }
             }
         }
     }
 
+    fn validate_depends(&self) -> Result<(), Box<dyn std::error::Error>> {
+        for depends in [
+            &self.depends,
+            &self.makedepends,
+            &self.checkdepends,
+            &self.provides,
+            &self.conflicts,
+            &self.replaces,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            depends::parse_all(depends)?;
+        }
+
+        if let Some(packages) = &self.packages {
+            for package in packages.iter() {
+                for depends in [&package.provides, &package.conflicts, &package.replaces]
+                    .into_iter()
+                    .flatten()
+                {
+                    depends::parse_all(depends)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn validate_sources(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(sources) = &self.sources {
+            for source in sources.iter() {
+                if let Some(Err(err)) = GitSource::parse(&source.url) {
+                    return Err(err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     fn compute_filenames(&mut self) {
         if let Some(ref mut sources) = self.sources {
             for source in sources.iter_mut() {
-                source.filename = match &source.filename {
-                    Some(f) => Some(f.to_string()),
-                    None => Some(downloader::get_url_basename(&source.url).unwrap()),
+                source.filename = match (&source.filename, source.git(), source.local_path()) {
+                    (Some(f), _, _) => Some(f.to_string()),
+                    (None, Some(git), _) => Some(git.name()),
+                    (None, None, Some(path)) => {
+                        path.file_name().map(|f| f.to_str().unwrap().to_string())
+                    }
+                    (None, None, None) => Some(downloader::get_url_basename(&source.url).unwrap()),
                 }
             }
         }
     }
 
+    fn source_by_filename(&self, filename: &str) -> Option<&PackageRecipeSource> {
+        self.sources
+            .as_ref()?
+            .iter()
+            .find(|s| s.filename.as_deref() == Some(filename))
+    }
+
     pub fn all_source_filenames(&self) -> Vec<&str> {
         let mut source_filenames: Vec<&str> = Vec::new();
 
-        match &self.sources {
-            Some(sources) => {
-                for source in sources.iter() {
-                    let filename = &source.filename.as_ref().unwrap().as_str();
-                    source_filenames.push(filename);
-                }
+        if let Some(sources) = &self.sources {
+            for source in sources.iter() {
+                let filename = source.filename.as_ref().unwrap().as_str();
+                source_filenames.push(filename);
             }
-            None => (),
-        };
+        }
 
         source_filenames
     }
 
-    pub async fn download_sources(&self, client: &Client) {
+    /// Downloads any sources into `srcdest` that aren't already there, or
+    /// whose checksums don't match because the cached copy is stale. Git
+    /// sources are mirrored there instead and fetched every time.
+    pub async fn download_sources(
+        &self,
+        client: &Client,
+        srcdest: &Path,
+        config: &Config,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut downloads = Vec::new();
+
         if let Some(sources) = &self.sources {
             for source in sources.iter() {
-                // TODO: error handling
-                let dl = downloader::download_file(client, source.url.as_str(), ".", false).await;
+                let path = self.source_path(source, srcdest);
+                if let Some(git) = source.git() {
+                    git.update_mirror(&path)?;
+                    continue;
+                }
+                if source.local_path().is_some() {
+                    if !path.exists() {
+                        return Err(format!("unable to find source {}", path.display()).into());
+                    }
+                    continue;
+                }
+                if source.is_cached(&path) {
+                    continue;
+                }
+
+                downloads.push(Download {
+                    urls: source.urls(),
+                    dest: path,
+                    sha256sum: source.sha256sum.clone(),
+                });
             }
         }
+
+        downloader::download_all(
+            client,
+            &downloads,
+            config.parallel_downloads,
+            &config.retry(),
+        )
+        .await
     }
 
-    pub fn verify_sources(&self) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn verify_sources(&self, srcdest: &Path) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(sources) = &self.sources {
             for source in sources.iter() {
-                match &source.sha256sum {
-                    Some(hash) => {
-                        if downloader::file_sha256sum_matches(
-                            &source.filename.as_ref().unwrap(),
-                            &hash,
-                        ) {
-                            continue;
-                        } else {
-                            return Err("hash doesn't match")?;
-                        }
-                    }
-                    None => continue,
+                let filename = source.filename.as_ref().unwrap();
+                // there's nothing to checksum, git already checks its objects
+                if source.git().is_some() {
+                    continue;
+                }
+                if !source.is_valid(&self.source_path(source, srcdest)) {
+                    return Err(format!("hash doesn't match for {}", filename).into());
                 }
             }
         }
@@ -145,33 +280,70 @@ impl PackageRecipe {
         Ok(())
     }
 
-    pub fn symlink_sources(&self, dest: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn symlink_sources(
+        &self,
+        srcdest: &Path,
+        dest: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(sources) = &self.sources {
             for source in sources.iter() {
-                let filename = &source.filename.as_ref().unwrap();
+                let filename = source.filename.as_ref().unwrap();
+
+                if let Some(git) = source.git() {
+                    git.checkout(
+                        &self.source_path(source, srcdest),
+                        &Path::new(dest).join(filename),
+                    )?;
+                    continue;
+                }
 
                 fs::symlink(
-                    std::fs::canonicalize(&filename).unwrap(),
-                    Path::new(dest).join(&filename),
-                )
-                .unwrap();
+                    std::fs::canonicalize(self.source_path(source, srcdest))?,
+                    Path::new(dest).join(filename),
+                )?;
             }
         }
 
         Ok(())
     }
 
+    /// Where a source is on disk, either in the source cache if it's
+    /// downloaded or mirrored or next to the recipe if it's local.
+    fn source_path(&self, source: &PackageRecipeSource, srcdest: &Path) -> PathBuf {
+        if let Some(git) = source.git() {
+            return srcdest.join(git.mirror_name());
+        }
+        match self.local_source(source) {
+            Some((dir, path)) => dir.join(path),
+            None => srcdest.join(source.filename.as_ref().unwrap()),
+        }
+    }
+
+    /// Splits a local source into the directory that it's in and the path
+    /// that it should have in the source package, which keeps sources that
+    /// are relative to the recipe where they were so that it can be rebuilt.
+    fn local_source(&self, source: &PackageRecipeSource) -> Option<(PathBuf, PathBuf)> {
+        let path = source.local_path()?;
+        if path.is_absolute() {
+            let dir = path.parent()?.to_path_buf();
+            Some((dir, PathBuf::from(path.file_name()?)))
+        } else {
+            Some((self.dir.clone(), path))
+        }
+    }
+
     pub fn extract_sources(&self, dest: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
         let mut extracted_sources = Vec::new();
 
         if let Some(sources) = &self.sources {
             for source in sources.iter() {
-                let filename = &source.filename.as_ref().unwrap();
-                if !is_archive(&filename) {
+                let filename = source.filename.as_ref().unwrap();
+                let path = Path::new(dest).join(filename);
+                if !is_archive(&path) {
                     continue;
                 }
 
-                let mut source = File::open(Path::new(dest).join(&filename)).unwrap();
+                let mut source = File::open(path).unwrap();
 
                 match compress_tools::uncompress_archive(
                     &mut source,
@@ -190,10 +362,12 @@ impl PackageRecipe {
     pub fn create_source_package(
         &self,
         srcdir: &str,
+        srcdest: &Path,
         recipe_file: &str,
         extracted_sources: Vec<String>,
     ) -> Result<bool, Box<dyn std::error::Error>> {
         let cwd = env::current_dir().unwrap().display().to_string();
+        let srcdest = std::fs::canonicalize(srcdest)?.display().to_string();
         let srcdir = Path::new(&cwd).join(srcdir);
 
         // TODO: put this in a tempdir to avoid cluttering srcdir
@@ -222,7 +396,7 @@ impl PackageRecipe {
             .arg(&cwd)
             .arg(format!("./{}", recipe_file));
 
-        let mut last_dir = &cwd;
+        let mut last_dir = cwd.clone();
 
         let mut entries = std::fs::read_dir(&srcdir)?
             .map(|res| res.map(|e| e.path()))
@@ -241,25 +415,32 @@ impl PackageRecipe {
                 // if we extracted the source we _don't_ want to include the
                 // archive symlink
                 continue;
-            } else if all_sources.iter().any(|&s| s == entry) {
-                println!("compressing in rootdir {}", &entry);
+            } else if let Some(source) =
+                self.source_by_filename(entry).filter(|s| s.git().is_none())
+            {
                 // we didn't extract the source because it wasn't an archive
                 // but we need to include the original, non-symlink from the
-                // root directory
-                if last_dir != &cwd {
-                    compress = compress.arg("-C").arg(&cwd);
-                    last_dir = &cwd;
+                // source cache or from beside the recipe (git checkouts aren't
+                // symlinks so they're included from srcdir below)
+                let (dir, path) = match self.local_source(source) {
+                    Some((dir, path)) => (dir.display().to_string(), path),
+                    None => (srcdest.clone(), PathBuf::from(entry)),
+                };
+                println!("compressing {} from {}", path.display(), &dir);
+                if last_dir != dir {
+                    compress = compress.arg("-C").arg(&dir);
+                    last_dir = dir;
                 }
-                compress = compress.arg(format!("./{}", &entry));
+                compress = compress.arg(format!("./{}", path.display()));
             } else {
                 println!("all_sources: {:?}", &all_sources);
                 println!("entry: {:?}", &entry);
                 println!("compressing in srcdir {}", &entry);
                 // this is _not_ in the source list explicitly which means it's
                 // the results of extracting an archive
-                if last_dir != &srcdir {
+                if last_dir != srcdir {
                     compress = compress.arg("-C").arg(&srcdir);
-                    last_dir = &srcdir;
+                    last_dir = srcdir.clone();
                 }
                 compress = compress.arg(format!("./{}", &entry));
             }
@@ -275,15 +456,67 @@ impl PackageRecipe {
 }
 
 impl PackageRecipeSource {
+    /// Whether the file at `path` can be used for this source, which is
+    /// anything if there's no checksum to compare against.
+    fn is_valid(&self, path: &Path) -> bool {
+        match &self.sha256sum {
+            Some(hash) => downloader::file_sha256sum_matches(path.to_str().unwrap(), hash),
+            None => path.exists(),
+        }
+    }
+
+    /// Whether the file at `path` can be used instead of downloading it again.
+    /// Every recipe shares the source cache and files in it are only named
+    /// after the source, so without a checksum there's no telling whether
+    /// it's the same file or another recipe's source with the same name.
+    fn is_cached(&self, path: &Path) -> bool {
+        self.sha256sum.is_some() && self.is_valid(path)
+    }
+
+    /// The path to a source that comes with the recipe instead of being
+    /// downloaded, which is anything that isn't a URL or is a `file://` one.
+    fn local_path(&self) -> Option<PathBuf> {
+        if let Some(path) = self.url.strip_prefix("file://") {
+            let url = Url::parse(&self.url).ok();
+            return match url.and_then(|url| url.to_file_path().ok()) {
+                Some(path) => Some(path),
+                None => Some(PathBuf::from(path)),
+            };
+        }
+
+        if self.url.contains("://") {
+            None
+        } else {
+            Some(PathBuf::from(&self.url))
+        }
+    }
+
+    /// The repository to clone for a `git+` source.
+    fn git(&self) -> Option<GitSource> {
+        GitSource::parse(&self.url)?.ok()
+    }
+
+    fn urls(&self) -> Vec<String> {
+        let mut urls = vec![self.url.clone()];
+        if let Some(mirrors) = &self.mirrors {
+            urls.extend(mirrors.iter().cloned());
+        }
+
+        urls
+    }
+
     fn variable_substitution(&mut self, find: &str, replace: &str) {
         let search = format!("${{{}}}", find);
         self.url = str::replace(&self.url, search.as_str(), replace);
 
-        match &mut self.filename {
-            Some(f) => {
-                self.filename = Some(str::replace(&f, search.as_str(), replace));
+        if let Some(ref mut mirrors) = self.mirrors {
+            for mirror in mirrors.iter_mut() {
+                *mirror = str::replace(mirror, search.as_str(), replace);
             }
-            None => (),
+        }
+
+        if let Some(f) = &self.filename {
+            self.filename = Some(str::replace(f, search.as_str(), replace));
         }
     }
 }
@@ -297,38 +530,158 @@ impl PackageRecipePackage {
         self.package.as_ref()
     }
 
-    pub fn create_package(&self, recipe: &PackageRecipe, pkgdir: &str) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn package_filename(&self, recipe: &PackageRecipe, compression: Compression) -> String {
+        format!(
+            "{}-{}-{}.pkg.tar.{}",
+            self.name,
+            recipe.full_version(),
+            recipe.package_arch(),
+            compression.extension()
+        )
+    }
+
+    pub fn package_info(
+        &self,
+        recipe: &PackageRecipe,
+        size: u64,
+        packager: Option<&String>,
+    ) -> PackageInfo {
+        let builddate = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        PackageInfo {
+            name: self.name.clone(),
+            base: recipe.name.clone(),
+            epoch: recipe.epoch,
+            version: recipe.version.clone(),
+            release: recipe.release,
+            description: match &self.description {
+                Some(description) => Some(description.clone()),
+                None => Some(recipe.description.clone()),
+            },
+            url: recipe.url.clone(),
+            arch: recipe.package_arch().to_string(),
+            packager: packager.cloned(),
+            builddate,
+            size,
+            license: recipe.license.clone().unwrap_or_default(),
+            depends: recipe.depends.clone().unwrap_or_default(),
+            makedepends: recipe.makedepends.clone().unwrap_or_default(),
+            checkdepends: recipe.checkdepends.clone().unwrap_or_default(),
+            // split packages can each declare their own relations, otherwise
+            // they inherit whatever the recipe declares
+            provides: self
+                .provides
+                .clone()
+                .or_else(|| recipe.provides.clone())
+                .unwrap_or_default(),
+            conflicts: self
+                .conflicts
+                .clone()
+                .or_else(|| recipe.conflicts.clone())
+                .unwrap_or_default(),
+            replaces: self
+                .replaces
+                .clone()
+                .or_else(|| recipe.replaces.clone())
+                .unwrap_or_default(),
+            backup: self.backup_files(),
+        }
+    }
+
+    /// The files that an admin is expected to edit, relative to the package
+    /// root like everything else in the metadata.
+    fn backup_files(&self) -> Vec<String> {
+        match &self.backup {
+            Some(backup) => backup
+                .iter()
+                .map(|f| f.trim_start_matches('/').to_string())
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn create_package(
+        &self,
+        recipe: &PackageRecipe,
+        pkgdir: &str,
+        config: &Config,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        // only regular files can be merged with an admin's changes
+        for file in self.backup_files().iter() {
+            if !Path::new(pkgdir).join(file).is_file() {
+                return Err(format!(
+                    "backup file {} is not in package {}",
+                    file, self.name
+                ))?;
+            }
+        }
+
+        let info = self.package_info(
+            recipe,
+            installed_size(Path::new(pkgdir))?,
+            config.packager.as_ref(),
+        );
+        std::fs::write(Path::new(pkgdir).join(PKGINFO_FILE), info.to_string())?;
+
+        let mtree = Mtree::from_pkgdir(Path::new(pkgdir))?;
+        std::fs::write(Path::new(pkgdir).join(MTREE_FILE), mtree.to_string())?;
+
+        if !self.scriptlets.is_empty() {
+            std::fs::write(
+                Path::new(pkgdir).join(INSTALL_FILE),
+                self.scriptlets.to_string(),
+            )?;
+        }
+
         let mut compress = Exec::cmd("fakeroot")
             .arg("--")
             .arg("bsdtar")
-            .arg("czf")
-            .arg(format!("{}-{}.pkg.tar.gz", &recipe.package_basename(),
-            "TODO"))
+            .arg("-c")
+            .arg(config.compression.flag())
+            .arg("-f")
+            .arg(self.package_filename(recipe, config.compression))
             .arg("-C")
-            .arg(&pkgdir);
+            .arg(pkgdir);
 
-        let mut entries = std::fs::read_dir(&pkgdir)?
+        let mut entries = std::fs::read_dir(pkgdir)?
             .map(|res| res.map(|e| e.path()))
             .collect::<Result<Vec<_>, std::io::Error>>()?;
         entries.sort();
 
         for entry in entries.iter() {
-            let entry = entry.strip_prefix(&pkgdir).unwrap().to_str().unwrap();
-            compress = compress.arg(&entry);
+            let entry = entry.strip_prefix(pkgdir).unwrap().to_str().unwrap();
+            compress = compress.arg(entry);
         }
 
-        println!("{:?}", &compress);
         let status = compress.join().unwrap();
-        println!("{:?}", status);
 
-        Ok(())
+        Ok(status.success())
     }
 
-    pub fn create_debug_package(&self) {
+    pub fn create_debug_package(&self) {}
+}
+
+fn installed_size(path: &Path) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut size = 0;
+
+    for entry in std::fs::read_dir(path)? {
+        let path = entry?.path();
+        let meta = path.symlink_metadata()?;
+
+        if meta.is_dir() {
+            size += installed_size(&path)?;
+        } else {
+            size += meta.len();
+        }
     }
+
+    Ok(size)
 }
 
-fn is_archive(path: &str) -> bool {
+fn is_archive(path: &Path) -> bool {
     // compress_tools will extract even regular files into "data", even
     // attempting to list the files does the same, so we need to exec the real
     // bsdtar and have it attempt to list the files where it will complain if
@@ -351,10 +704,18 @@ fn is_archive(path: &str) -> bool {
 mod tests {
     use super::*;
 
+    use std::collections::HashMap;
+
+    use crate::package::bash;
+    use crate::test_server::{Response, TestServer};
+
     #[test]
     fn test_variable_substitution() {
         let mut s = PackageRecipeSource {
             url: String::from("${url}/archive/${pkgname}-${pkgver}.tar.gz"),
+            mirrors: Some(vec![String::from(
+                "https://mirror.example.com/${pkgname}-${pkgver}.tar.gz",
+            )]),
             sha256sum: None,
             filename: Some(String::from("${pkgname}.tgz")),
         };
@@ -364,6 +725,13 @@ mod tests {
         s.variable_substitution("pkgver", "1.0");
 
         assert_eq!(s.url, "https://example.com/archive/test-1.0.tar.gz");
+        assert_eq!(
+            s.urls(),
+            vec![
+                "https://example.com/archive/test-1.0.tar.gz",
+                "https://mirror.example.com/test-1.0.tar.gz"
+            ]
+        );
         assert_eq!(s.filename.unwrap(), "test.tgz");
     }
 
@@ -381,12 +749,17 @@ mod tests {
             depends: None,
             makedepends: None,
             checkdepends: None,
+            provides: None,
+            conflicts: None,
+            replaces: None,
             sources: None,
             source: None,
             prepare: None,
             build: None,
             check: None,
+            pkgver: None,
             packages: None,
+            dir: PathBuf::new(),
         };
         assert_eq!(recipe.package_basename(), "testpkg-1:1.2.3-4");
     }
@@ -405,13 +778,357 @@ mod tests {
             depends: None,
             makedepends: None,
             checkdepends: None,
+            provides: None,
+            conflicts: None,
+            replaces: None,
             source: None,
             sources: None,
             prepare: None,
             build: None,
             check: None,
+            pkgver: None,
             packages: None,
+            dir: PathBuf::new(),
         };
         assert_eq!(recipe.package_basename(), "testpkg-1.2.3-4");
     }
+
+    #[test]
+    fn test_set_version() {
+        let mut recipe: PackageRecipe =
+            serde_yaml::from_str("name: testpkg\nversion: \"0\"\nrelease: 1\ndescription: test\n")
+                .unwrap();
+
+        recipe.set_version("1.2.r3.gabc123").unwrap();
+        assert_eq!(recipe.full_version(), "1.2.r3.gabc123-1");
+
+        for version in ["", "1.2-3", "1:2", "1.2 3", "v1/2"] {
+            assert!(recipe.set_version(version).is_err());
+        }
+        assert_eq!(recipe.version(), "1.2.r3.gabc123");
+    }
+
+    #[test]
+    fn test_package_scriptlets() {
+        let package: PackageRecipePackage = serde_yaml::from_str(
+            "name: testpkg\npost_install: echo installed\npre_remove: echo removing\n",
+        )
+        .unwrap();
+
+        assert_eq!(package.scriptlets.post_install.unwrap(), "echo installed");
+        assert_eq!(package.scriptlets.pre_remove.unwrap(), "echo removing");
+        assert!(package.scriptlets.pre_install.is_none());
+    }
+
+    #[test]
+    fn test_package_backup() {
+        let package: PackageRecipePackage = serde_yaml::from_str(
+            "name: testpkg\nbackup:\n  - /etc/testpkg.conf\n  - etc/testpkg/other.conf\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            package.backup_files(),
+            vec!["etc/testpkg.conf", "etc/testpkg/other.conf"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_shared_source_cache() {
+        let dir = std::env::temp_dir().join(format!("mpm-recipe-cache-{}", std::process::id()));
+        let srcdest = dir.join("sources");
+        std::fs::create_dir_all(&srcdest).unwrap();
+
+        let server = TestServer::start(|request| Response::new(200, request.path.as_bytes()));
+        let client = Client::builder().no_proxy().build().unwrap();
+
+        std::fs::write(dir.join("expected"), "/b/v1.0.tar.gz").unwrap();
+        let sha256sum = downloader::file_sha256sum(dir.join("expected").to_str().unwrap()).unwrap();
+
+        // two projects that both name their release archives after the version
+        let recipe = |name: &str, project: &str, sha256sum: Option<&str>| {
+            let path = dir.join(format!("{}.yaml", name));
+            let mut source = format!("  - url: {}/{}/v1.0.tar.gz\n", server.url, project);
+            if let Some(sha256sum) = sha256sum {
+                source += &format!("    sha256sum: {}\n", sha256sum);
+            }
+            std::fs::write(
+                &path,
+                format!(
+                    "name: {}\nversion: \"1.0\"\nrelease: 1\ndescription: test\nsources:\n{}",
+                    name, source
+                ),
+            )
+            .unwrap();
+            PackageRecipe::from_file(path.to_str().unwrap()).unwrap()
+        };
+        let cached = srcdest.join("v1.0.tar.gz");
+
+        recipe("a", "a", None)
+            .download_sources(&client, &srcdest, &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&cached).unwrap(), b"/a/v1.0.tar.gz");
+
+        // without a checksum the other recipe's file can't be trusted
+        let b = recipe("b", "b", Some(&sha256sum));
+        b.download_sources(&client, &srcdest, &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&cached).unwrap(), b"/b/v1.0.tar.gz");
+
+        // but one that matches the checksum is used as it is
+        b.download_sources(&client, &srcdest, &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(server.requests().len(), 2);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_extract_sources() {
+        let dir = std::env::temp_dir().join(format!("mpm-recipe-extract-{}", std::process::id()));
+        let srcdest = dir.join("sources");
+        let srcdir = dir.join("src");
+        std::fs::create_dir_all(&srcdest).unwrap();
+        std::fs::create_dir_all(&srcdir).unwrap();
+
+        let tarball = std::fs::read("tests/fixtures/src.tar.gz").unwrap();
+        let server = TestServer::start(move |request| match request.path.as_str() {
+            "/src.tar.gz" => Response::new(200, &tarball),
+            _ => Response::new(200, b"not an archive"),
+        });
+
+        let recipe_file = dir.join("pkgrecipe.yaml");
+        std::fs::write(
+            &recipe_file,
+            format!(
+                "name: test\nversion: \"1.0\"\nrelease: 1\ndescription: test\nsources:\n  \
+                 - url: {0}/src.tar.gz\n  \
+                 - url: {0}/fix.patch\n",
+                server.url
+            ),
+        )
+        .unwrap();
+        let recipe = PackageRecipe::from_file(recipe_file.to_str().unwrap()).unwrap();
+
+        // the sources are only linked into srcdir from the cache
+        let client = Client::builder().no_proxy().build().unwrap();
+        recipe
+            .download_sources(&client, &srcdest, &Config::default())
+            .await
+            .unwrap();
+        recipe
+            .symlink_sources(&srcdest, srcdir.to_str().unwrap())
+            .unwrap();
+        let extracted = recipe.extract_sources(srcdir.to_str().unwrap()).unwrap();
+        assert_eq!(extracted, vec!["src.tar.gz"]);
+        assert!(srcdir.join("main.c").is_file());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_sources() {
+        let dir = std::env::temp_dir().join(format!("mpm-recipe-local-{}", std::process::id()));
+        let srcdest = dir.join("sources");
+        let srcdir = dir.join("src");
+        std::fs::create_dir_all(dir.join("files")).unwrap();
+        std::fs::create_dir_all(&srcdest).unwrap();
+        std::fs::create_dir_all(&srcdir).unwrap();
+
+        std::fs::write(dir.join("fix.patch"), "patch").unwrap();
+        std::fs::write(dir.join("files/foo.service"), "service").unwrap();
+        std::fs::write(dir.join("abs.conf"), "conf").unwrap();
+        let sha256sum =
+            downloader::file_sha256sum(dir.join("fix.patch").to_str().unwrap()).unwrap();
+
+        let recipe_file = dir.join("pkgrecipe.yaml");
+        std::fs::write(
+            &recipe_file,
+            format!(
+                "name: test\nversion: \"1.0\"\nrelease: 1\ndescription: test\nsources:\n  \
+                 - url: fix.patch\n    sha256sum: {}\n  \
+                 - url: files/foo.service\n  \
+                 - url: file://{}\n",
+                sha256sum,
+                dir.join("abs.conf").display()
+            ),
+        )
+        .unwrap();
+
+        let recipe = PackageRecipe::from_file(recipe_file.to_str().unwrap()).unwrap();
+        assert_eq!(
+            recipe.all_source_filenames(),
+            vec!["fix.patch", "foo.service", "abs.conf"]
+        );
+
+        let sources = recipe.sources.as_ref().unwrap();
+        let dir = std::fs::canonicalize(&dir).unwrap();
+        assert_eq!(
+            recipe.local_source(&sources[1]),
+            Some((dir.clone(), PathBuf::from("files/foo.service")))
+        );
+        assert_eq!(
+            recipe.local_source(&sources[2]),
+            Some((dir.clone(), PathBuf::from("abs.conf")))
+        );
+
+        // nothing is downloaded for them
+        let client = Client::builder().no_proxy().build().unwrap();
+        recipe
+            .download_sources(&client, &srcdest, &Config::default())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_dir(&srcdest).unwrap().count(), 0);
+
+        recipe.verify_sources(&srcdest).unwrap();
+        recipe
+            .symlink_sources(&srcdest, srcdir.to_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(srcdir.join("foo.service")).unwrap(),
+            "service"
+        );
+
+        // they're checksummed like any other source
+        std::fs::write(dir.join("fix.patch"), "changed").unwrap();
+        assert!(recipe.verify_sources(&srcdest).is_err());
+
+        std::fs::remove_file(dir.join("abs.conf")).unwrap();
+        assert!(recipe
+            .download_sources(&client, &srcdest, &Config::default())
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_git_sources() {
+        let dir = std::env::temp_dir().join(format!("mpm-recipe-git-{}", std::process::id()));
+        let work = dir.join("work");
+        let srcdest = dir.join("sources");
+        let srcdir = dir.join("src");
+        std::fs::create_dir_all(&work).unwrap();
+        std::fs::create_dir_all(&srcdest).unwrap();
+        std::fs::create_dir_all(&srcdir).unwrap();
+
+        std::fs::write(work.join("file"), "contents").unwrap();
+        for args in [
+            vec!["init", "--quiet"],
+            vec!["add", "file"],
+            vec!["commit", "--quiet", "-m", "first"],
+            vec!["tag", "v1.2"],
+            vec!["clone", "--quiet", "--bare", ".", "../repo.git"],
+        ] {
+            let status = Exec::cmd("git")
+                .cwd(&work)
+                .args(&args)
+                .env("GIT_AUTHOR_NAME", "test")
+                .env("GIT_AUTHOR_EMAIL", "test@example.com")
+                .env("GIT_COMMITTER_NAME", "test")
+                .env("GIT_COMMITTER_EMAIL", "test@example.com")
+                .join()
+                .unwrap();
+            assert!(status.success());
+        }
+
+        let recipe_file = dir.join("pkgrecipe.yaml");
+        std::fs::write(
+            &recipe_file,
+            format!(
+                "name: test\nversion: \"0\"\nrelease: 1\ndescription: test\nsources:\n  \
+                 - url: git+file://{}#tag=v1.2\n\
+                 pkgver: cd \"$srcdir/repo\" && git describe --tags | sed 's/^v//'\n",
+                dir.join("repo.git").display()
+            ),
+        )
+        .unwrap();
+
+        let mut recipe = PackageRecipe::from_file(recipe_file.to_str().unwrap()).unwrap();
+        assert_eq!(recipe.all_source_filenames(), vec!["repo"]);
+
+        // the mirror goes in the source cache and the checkout in srcdir
+        let client = Client::builder().no_proxy().build().unwrap();
+        recipe
+            .download_sources(&client, &srcdest, &Config::default())
+            .await
+            .unwrap();
+        let git = recipe.sources.as_ref().unwrap()[0].git().unwrap();
+        assert!(srcdest.join(git.mirror_name()).join("HEAD").exists());
+        recipe.verify_sources(&srcdest).unwrap();
+        recipe
+            .symlink_sources(&srcdest, srcdir.to_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(srcdir.join("repo").join("file")).unwrap(),
+            "contents"
+        );
+
+        // another repository with the same name gets a mirror of its own
+        std::fs::write(work.join("file"), "other").unwrap();
+        for args in [
+            vec!["commit", "--quiet", "-am", "second"],
+            vec!["clone", "--quiet", "--bare", ".", "../other/repo.git"],
+        ] {
+            let status = Exec::cmd("git")
+                .cwd(&work)
+                .args(&args)
+                .env("GIT_AUTHOR_NAME", "test")
+                .env("GIT_AUTHOR_EMAIL", "test@example.com")
+                .env("GIT_COMMITTER_NAME", "test")
+                .env("GIT_COMMITTER_EMAIL", "test@example.com")
+                .join()
+                .unwrap();
+            assert!(status.success());
+        }
+        let other_file = dir.join("other").join("pkgrecipe.yaml");
+        std::fs::write(
+            &other_file,
+            format!(
+                "name: other\nversion: \"0\"\nrelease: 1\ndescription: test\nsources:\n  \
+                 - url: git+file://{}\n",
+                dir.join("other").join("repo.git").display()
+            ),
+        )
+        .unwrap();
+        let other = PackageRecipe::from_file(other_file.to_str().unwrap()).unwrap();
+        let other_srcdir = dir.join("other").join("src");
+        std::fs::create_dir_all(&other_srcdir).unwrap();
+        other
+            .download_sources(&client, &srcdest, &Config::default())
+            .await
+            .unwrap();
+        other
+            .symlink_sources(&srcdest, other_srcdir.to_str().unwrap())
+            .unwrap();
+        assert_eq!(
+            std::fs::read_to_string(other_srcdir.join("repo").join("file")).unwrap(),
+            "other"
+        );
+
+        let full_srcdir = srcdir.to_str().unwrap().to_string();
+        let mut vars = HashMap::new();
+        vars.insert("srcdir", &full_srcdir);
+        let version = bash::run_script_output(
+            srcdir.to_str().unwrap(),
+            recipe.pkgver.as_ref().unwrap(),
+            &vars,
+        )
+        .unwrap();
+        recipe.set_version(&version).unwrap();
+        assert_eq!(recipe.full_version(), "1.2-1");
+
+        std::fs::write(
+            &recipe_file,
+            "name: test\nversion: \"0\"\nrelease: 1\ndescription: test\nsources:\n  \
+             - url: git+https://example.com/repo.git#rev=abc\n",
+        )
+        .unwrap();
+        assert!(PackageRecipe::from_file(recipe_file.to_str().unwrap()).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::bash;
+
+/// Name of the file holding the scriptlets at the root of a package archive.
+pub static INSTALL_FILE: &str = ".INSTALL";
+
+/// The points in a package's life on a system where it can run a script.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Hook {
+    PreInstall,
+    PostInstall,
+    PreUpgrade,
+    PostUpgrade,
+    PreRemove,
+    PostRemove,
+}
+
+impl std::fmt::Display for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Hook::PreInstall => "pre_install",
+            Hook::PostInstall => "post_install",
+            Hook::PreUpgrade => "pre_upgrade",
+            Hook::PostUpgrade => "post_upgrade",
+            Hook::PreRemove => "pre_remove",
+            Hook::PostRemove => "post_remove",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Scripts that run on the target system when the package is installed,
+/// upgraded or removed, written into the archive as `.INSTALL`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Scriptlets {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_install: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_install: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_upgrade: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_upgrade: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pre_remove: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_remove: Option<String>,
+}
+
+impl Scriptlets {
+    /// Reads the scriptlets from a package archive, returning `None` if the
+    /// package doesn't have any.
+    pub fn from_archive(path: &str) -> Result<Option<Self>, Box<dyn std::error::Error>> {
+        let mut source = File::open(path)?;
+        let files = match compress_tools::list_archive_files(&mut source) {
+            Ok(files) => files,
+            Err(err) => return Err(format!("unable to read {}: {}", path, err))?,
+        };
+
+        if !files
+            .iter()
+            .any(|f| f.trim_start_matches("./") == INSTALL_FILE)
+        {
+            return Ok(None);
+        }
+
+        let mut source = File::open(path)?;
+        let mut contents = Vec::new();
+        if let Err(err) =
+            compress_tools::uncompress_archive_file(&mut source, &mut contents, INSTALL_FILE)
+        {
+            return Err(format!("unable to read scriptlets from {}: {}", path, err))?;
+        }
+
+        Ok(Some(Self::parse(&String::from_utf8(contents)?)?))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        match serde_yaml::from_str(contents) {
+            Ok(scriptlets) => Ok(scriptlets),
+            Err(err) => Err(format!("invalid scriptlets: {}", err))?,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Scriptlets::default()
+    }
+
+    pub fn get(&self, hook: Hook) -> Option<&String> {
+        match hook {
+            Hook::PreInstall => self.pre_install.as_ref(),
+            Hook::PostInstall => self.post_install.as_ref(),
+            Hook::PreUpgrade => self.pre_upgrade.as_ref(),
+            Hook::PostUpgrade => self.post_upgrade.as_ref(),
+            Hook::PreRemove => self.pre_remove.as_ref(),
+            Hook::PostRemove => self.post_remove.as_ref(),
+        }
+    }
+
+    /// Runs the script for the hook, if there is one, on the system installed
+    /// in `root` with `old_version` and `new_version` set when they apply.
+    pub fn run(
+        &self,
+        hook: Hook,
+        root: &Path,
+        name: &str,
+        old_version: Option<&str>,
+        new_version: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let script = match self.get(hook) {
+            Some(script) => script,
+            None => return Ok(()),
+        };
+
+        // like a real system the root needs its own shell to run anything
+        if root != Path::new("/")
+            && !root.join("bin/bash").exists()
+            && !root.join("usr/bin/bash").exists()
+        {
+            eprintln!(
+                "warning: skipping {} scriptlet for {}, no bash in {}",
+                hook,
+                name,
+                root.display()
+            );
+            return Ok(());
+        }
+
+        let name = name.to_string();
+        let old_version = old_version.map(|v| v.to_string());
+        let new_version = new_version.map(|v| v.to_string());
+
+        let mut vars = HashMap::new();
+        vars.insert("pkgname", &name);
+        if let Some(old_version) = &old_version {
+            vars.insert("old_version", old_version);
+        }
+        if let Some(new_version) = &new_version {
+            vars.insert("new_version", new_version);
+        }
+
+        if !bash::run_script_in_root(root, script, &vars) {
+            return Err(format!("{} scriptlet for {} failed", hook, name).into());
+        }
+
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Scriptlets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let yaml = serde_yaml::to_string(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", yaml)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn test_roundtrip() {
+        let scriptlets = Scriptlets {
+            post_install: Some(String::from("echo installed\n")),
+            pre_remove: Some(String::from("echo removing\n")),
+            ..Default::default()
+        };
+
+        let output = scriptlets.to_string();
+        assert!(!output.contains("pre_install"));
+        assert_eq!(Scriptlets::parse(&output).unwrap(), scriptlets);
+        assert!(!scriptlets.is_empty());
+        assert!(Scriptlets::default().is_empty());
+    }
+
+    #[test]
+    fn test_run() {
+        let dir = std::env::temp_dir().join(format!("mpm-scriptlet-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("output");
+
+        let scriptlets = Scriptlets {
+            post_upgrade: Some(format!(
+                "echo \"$pkgname $old_version $new_version\" > '{}'\n",
+                output.display()
+            )),
+            pre_remove: Some(String::from("false\n")),
+            ..Default::default()
+        };
+
+        scriptlets
+            .run(
+                Hook::PostUpgrade,
+                Path::new("/"),
+                "foo",
+                Some("1.0-1"),
+                Some("1.1-1"),
+            )
+            .unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "foo 1.0-1 1.1-1\n");
+
+        let err = scriptlets
+            .run(Hook::PreRemove, Path::new("/"), "foo", Some("1.1-1"), None)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "pre_remove scriptlet for foo failed");
+
+        // nothing to run
+        scriptlets
+            .run(Hook::PreInstall, Path::new("/"), "foo", None, Some("1.1-1"))
+            .unwrap();
+
+        // a root without a shell can't run anything
+        scriptlets
+            .run(Hook::PreRemove, &dir, "foo", Some("1.1-1"), None)
+            .unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,289 @@
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+use subprocess::{Exec, NullFile, Redirection};
+
+/// What to check out of a repository, from the fragment of its url.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Revision {
+    Head,
+    Tag(String),
+    Commit(String),
+    Branch(String),
+}
+
+impl std::fmt::Display for Revision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Revision::Head => write!(f, "HEAD"),
+            Revision::Tag(tag) => write!(f, "tag {}", tag),
+            Revision::Commit(commit) => write!(f, "commit {}", commit),
+            Revision::Branch(branch) => write!(f, "branch {}", branch),
+        }
+    }
+}
+
+/// A source that's cloned from a git repository instead of downloaded, given
+/// as `git+https://example.com/repo.git#tag=v1.2` (or `#commit=`/`#branch=`).
+///
+/// Each repository is kept as a bare mirror in the source cache so that later
+/// builds only have to fetch what's changed since.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GitSource {
+    pub url: String,
+    pub revision: Revision,
+}
+
+impl GitSource {
+    /// Parses a source url, returning `None` if it isn't a git one.
+    pub fn parse(url: &str) -> Option<Result<Self, Box<dyn std::error::Error>>> {
+        let url = url.strip_prefix("git+")?;
+
+        let (url, revision) = match url.split_once('#') {
+            Some((url, fragment)) => match fragment.split_once('=') {
+                Some(("tag", tag)) if !tag.is_empty() => (url, Revision::Tag(tag.to_string())),
+                Some(("commit", commit)) if !commit.is_empty() => {
+                    (url, Revision::Commit(commit.to_string()))
+                }
+                Some(("branch", branch)) if !branch.is_empty() => {
+                    (url, Revision::Branch(branch.to_string()))
+                }
+                _ => return Some(Err(format!("invalid revision in git source {}", url).into())),
+            },
+            None => (url, Revision::Head),
+        };
+
+        Some(Ok(GitSource {
+            url: url.to_string(),
+            revision,
+        }))
+    }
+
+    /// The name of the repository, which is what the checkout is called.
+    pub fn name(&self) -> String {
+        let path = self.url.trim_end_matches('/');
+        let name = path.rsplit('/').next().unwrap_or(path);
+        name.trim_end_matches(".git").to_string()
+    }
+
+    /// What the mirror is called in the source cache, which has to tell
+    /// apart repositories that have the same name but come from different
+    /// places.
+    pub fn mirror_name(&self) -> String {
+        let hash = hex::encode(Sha256::digest(self.url.as_bytes()));
+        format!("{}-{}.git", self.name(), &hash[..16])
+    }
+
+    /// Creates the bare mirror of the repository in `mirror`, or brings it up
+    /// to date if it already exists.
+    pub fn update_mirror(&self, mirror: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        if !mirror.exists() {
+            println!("cloning {}", self.url);
+            return git(Exec::cmd("git")
+                .arg("clone")
+                .arg("--quiet")
+                .arg("--mirror")
+                .arg(&self.url)
+                .arg(mirror));
+        }
+
+        // a commit can't change so there's no need to go looking for it again
+        if let Revision::Commit(commit) = &self.revision {
+            if has_commit(mirror, commit) {
+                return Ok(());
+            }
+        }
+
+        println!("fetching {}", self.url);
+        git(Exec::cmd("git")
+            .arg("--git-dir")
+            .arg(mirror)
+            .arg("fetch")
+            .arg("--quiet")
+            .arg("--prune")
+            .arg("origin"))
+    }
+
+    /// Clones the mirror into `dest` and checks out the revision.
+    pub fn checkout(&self, mirror: &Path, dest: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        git(Exec::cmd("git")
+            .arg("clone")
+            .arg("--quiet")
+            .arg("--no-checkout")
+            .arg(mirror)
+            .arg(dest))?;
+
+        let revision = match &self.revision {
+            Revision::Head => String::from("HEAD"),
+            Revision::Tag(tag) => format!("refs/tags/{}^{{commit}}", tag),
+            Revision::Commit(commit) => format!("{}^{{commit}}", commit),
+            Revision::Branch(branch) => format!("refs/remotes/origin/{}", branch),
+        };
+
+        match git(Exec::cmd("git")
+            .cwd(dest)
+            .arg("checkout")
+            .arg("--quiet")
+            .arg("--detach")
+            .arg(&revision))
+        {
+            Ok(_) => Ok(()),
+            Err(_) => Err(format!(
+                "unable to check out {} of {}",
+                self.revision, self.url
+            ))?,
+        }
+    }
+}
+
+fn has_commit(mirror: &Path, commit: &str) -> bool {
+    Exec::cmd("git")
+        .arg("--git-dir")
+        .arg(mirror)
+        .arg("cat-file")
+        .arg("-e")
+        .arg(format!("{}^{{commit}}", commit))
+        .stdout(NullFile)
+        .stderr(NullFile)
+        .join()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn git(command: Exec) -> Result<(), Box<dyn std::error::Error>> {
+    let capture = command
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Merge)
+        .capture()?;
+    if !capture.success() {
+        return Err(format!("git failed: {}", capture.stdout_str().trim()).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn run(dir: &Path, args: &[&str]) -> String {
+        let capture = Exec::cmd("git")
+            .cwd(dir)
+            .args(args)
+            .env("GIT_AUTHOR_NAME", "test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Merge)
+            .capture()
+            .unwrap();
+        assert!(capture.success(), "{}", capture.stdout_str());
+        capture.stdout_str().trim().to_string()
+    }
+
+    /// Makes a bare repository with a tagged commit on main and another on a
+    /// branch, returning it and the tagged commit.
+    fn test_repo(dir: &Path) -> (PathBuf, String) {
+        let work = dir.join("work");
+        fs::create_dir_all(&work).unwrap();
+        run(&work, &["init", "--quiet", "--initial-branch", "main"]);
+
+        fs::write(work.join("file"), "1").unwrap();
+        run(&work, &["add", "file"]);
+        run(&work, &["commit", "--quiet", "-m", "first"]);
+        run(&work, &["tag", "v1.0"]);
+        let tagged = run(&work, &["rev-parse", "HEAD"]);
+
+        run(&work, &["checkout", "--quiet", "-b", "next"]);
+        fs::write(work.join("file"), "2").unwrap();
+        run(&work, &["commit", "--quiet", "-am", "second"]);
+        run(&work, &["checkout", "--quiet", "main"]);
+
+        let bare = dir.join("repo.git");
+        run(
+            dir,
+            &["clone", "--quiet", "--bare", "work", bare.to_str().unwrap()],
+        );
+
+        (bare, tagged)
+    }
+
+    #[test]
+    fn test_parse() {
+        let source = GitSource::parse("git+https://example.com/foo/bar.git#tag=v1.2")
+            .unwrap()
+            .unwrap();
+        assert_eq!(source.url, "https://example.com/foo/bar.git");
+        assert_eq!(source.revision, Revision::Tag(String::from("v1.2")));
+        assert_eq!(source.name(), "bar");
+        assert!(source.mirror_name().starts_with("bar-"));
+
+        let source = GitSource::parse("git+file:///srv/repo").unwrap().unwrap();
+        assert_eq!(source.revision, Revision::Head);
+        assert_eq!(source.name(), "repo");
+
+        // the revision doesn't matter but where it comes from does
+        let mirror_name = |url: &str| GitSource::parse(url).unwrap().unwrap().mirror_name();
+        assert_eq!(
+            mirror_name("git+https://example.com/foo/bar.git#tag=v1.2"),
+            mirror_name("git+https://example.com/foo/bar.git#branch=main")
+        );
+        assert_ne!(
+            mirror_name("git+https://example.com/foo/bar.git"),
+            mirror_name("git+https://example.com/baz/bar.git")
+        );
+
+        assert!(GitSource::parse("https://example.com/bar.tar.gz").is_none());
+        assert!(GitSource::parse("git+https://example.com/bar.git#tag=")
+            .unwrap()
+            .is_err());
+        assert!(GitSource::parse("git+https://example.com/bar.git#rev=abc")
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_checkout() {
+        let dir = std::env::temp_dir().join(format!("mpm-vcs-{}", std::process::id()));
+        if dir.exists() {
+            fs::remove_dir_all(&dir).unwrap();
+        }
+        fs::create_dir_all(&dir).unwrap();
+        let (bare, tagged) = test_repo(&dir);
+        let url = format!("git+file://{}", bare.display());
+        let mirror = dir.join("mirror");
+
+        let checkout = |fragment: &str, name: &str| {
+            let source = GitSource::parse(&format!("{}{}", url, fragment))
+                .unwrap()
+                .unwrap();
+            source.update_mirror(&mirror).unwrap();
+            source.checkout(&mirror, &dir.join(name)).unwrap();
+            fs::read_to_string(dir.join(name).join("file")).unwrap()
+        };
+
+        assert_eq!(checkout("", "head"), "1");
+        assert_eq!(checkout("#tag=v1.0", "tag"), "1");
+        assert_eq!(checkout("#branch=next", "branch"), "2");
+        assert_eq!(checkout(&format!("#commit={}", tagged), "commit"), "1");
+
+        // new commits are fetched into the existing mirror
+        let work = dir.join("work");
+        fs::write(work.join("file"), "3").unwrap();
+        run(&work, &["commit", "--quiet", "-am", "third"]);
+        run(&work, &["push", "--quiet", bare.to_str().unwrap(), "main"]);
+        assert_eq!(checkout("#branch=main", "main"), "3");
+
+        let source = GitSource::parse(&format!("{}#tag=missing", url))
+            .unwrap()
+            .unwrap();
+        source.update_mirror(&mirror).unwrap();
+        assert!(source.checkout(&mirror, &dir.join("missing")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
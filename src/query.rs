@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+use clap::ArgMatches;
+use serde::Serialize;
+
+use super::db::{self, InstallReason, InstalledPackage, LocalDatabase};
+use super::depends;
+use super::upgrade::format_size;
+
+/// A package in the `--json` output of a plain query or `--orphans`.
+#[derive(Serialize)]
+struct PackageVersion<'a> {
+    name: &'a str,
+    version: String,
+}
+
+/// The `--json` output of `--info`, which has everything that's printed for
+/// humans but with the raw values.
+#[derive(Serialize)]
+struct PackageDetails<'a> {
+    name: &'a str,
+    version: String,
+    description: Option<&'a str>,
+    url: Option<&'a str>,
+    arch: &'a str,
+    license: &'a [String],
+    depends: &'a [String],
+    required_by: Vec<String>,
+    provides: &'a [String],
+    conflicts: &'a [String],
+    replaces: &'a [String],
+    installed_size: u64,
+    build_date: u64,
+    install_date: u64,
+    reason: InstallReason,
+    backup: &'a BTreeMap<String, String>,
+}
+
+/// The `--json` output of `--files`.
+#[derive(Serialize)]
+struct PackageFiles<'a> {
+    name: &'a str,
+    files: Vec<String>,
+}
+
+/// The `--json` output of `--owns`, with every package that owns the path.
+#[derive(Serialize)]
+struct PathOwners<'a> {
+    path: String,
+    owners: Vec<&'a str>,
+}
+
+pub fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let (root, dbpath) = db::paths(cli);
+    let installed = LocalDatabase::open(&dbpath)?.packages()?;
+    let json = cli.is_present("json");
+
+    if let Some(paths) = cli.values_of("owns") {
+        return owns(&root, &installed, &paths.collect::<Vec<_>>(), json);
+    }
+
+    let packages = match cli.values_of("package") {
+        Some(names) => {
+            let mut packages = Vec::new();
+            for name in names {
+                match installed.iter().find(|p| p.name == name) {
+                    Some(package) => packages.push(package),
+                    None => return Err(format!("{} is not installed", name).into()),
+                }
+            }
+            packages
+        }
+        None => installed.iter().collect(),
+    };
+
+    if cli.is_present("info") {
+        print_info(&installed, &packages, json)
+    } else if cli.is_present("files") {
+        print_files(&root, &packages, json)
+    } else if cli.is_present("orphans") {
+        let orphans = orphans(&installed)?;
+        let packages: Vec<&InstalledPackage> = packages
+            .into_iter()
+            .filter(|p| orphans.iter().any(|o| o.name == p.name))
+            .collect();
+        print_list(&packages, json)
+    } else {
+        print_list(&packages, json)
+    }
+}
+
+fn print_list(
+    packages: &[&InstalledPackage],
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        let list: Vec<PackageVersion> = packages
+            .iter()
+            .map(|p| PackageVersion {
+                name: &p.name,
+                version: p.full_version(),
+            })
+            .collect();
+        println!("{}", serde_json::to_string(&list)?);
+        return Ok(());
+    }
+
+    for package in packages.iter() {
+        println!("{} {}", package.name, package.full_version());
+    }
+
+    Ok(())
+}
+
+fn print_info(
+    installed: &[InstalledPackage],
+    packages: &[&InstalledPackage],
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut infos = Vec::new();
+
+    for (i, package) in packages.iter().enumerate() {
+        let required_by = required_by(installed, package)?;
+
+        if json {
+            infos.push(PackageDetails {
+                name: &package.name,
+                version: package.full_version(),
+                description: package.description.as_deref(),
+                url: package.url.as_deref(),
+                arch: &package.arch,
+                license: &package.license,
+                depends: &package.depends,
+                required_by,
+                provides: &package.provides,
+                conflicts: &package.conflicts,
+                replaces: &package.replaces,
+                installed_size: package.size,
+                build_date: package.builddate,
+                install_date: package.install_date,
+                reason: package.reason,
+                backup: &package.backup,
+            });
+            continue;
+        }
+
+        if i > 0 {
+            println!();
+        }
+
+        let reason = match package.reason {
+            InstallReason::Explicit => "Explicitly installed",
+            InstallReason::Dependency => "Installed as a dependency for another package",
+        };
+        let backup: Vec<String> = package.backup.keys().cloned().collect();
+
+        print_field("Name", &package.name);
+        print_field("Version", &package.full_version());
+        print_field(
+            "Description",
+            package.description.as_deref().unwrap_or("None"),
+        );
+        print_field("Architecture", &package.arch);
+        print_field("URL", package.url.as_deref().unwrap_or("None"));
+        print_field("Licenses", &join(&package.license));
+        print_field("Provides", &join(&package.provides));
+        print_field("Depends On", &join(&package.depends));
+        print_field("Required By", &join(&required_by));
+        print_field("Conflicts With", &join(&package.conflicts));
+        print_field("Replaces", &join(&package.replaces));
+        print_field("Installed Size", &format_size(package.size));
+        print_field("Build Date", &format_date(package.builddate));
+        print_field("Install Date", &format_date(package.install_date));
+        print_field("Install Reason", reason);
+        print_field("Backup Files", &join(&backup));
+    }
+
+    if json {
+        println!("{}", serde_json::to_string(&infos)?);
+    }
+
+    Ok(())
+}
+
+fn print_field(name: &str, value: &str) {
+    println!("{:<15}: {}", name, value);
+}
+
+fn join<S: AsRef<str>>(values: &[S]) -> String {
+    if values.is_empty() {
+        return String::from("None");
+    }
+
+    values
+        .iter()
+        .map(|v| v.as_ref())
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+fn print_files(
+    root: &Path,
+    packages: &[&InstalledPackage],
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if json {
+        let list: Vec<PackageFiles> = packages
+            .iter()
+            .map(|p| PackageFiles {
+                name: &p.name,
+                files: p
+                    .files
+                    .iter()
+                    .map(|f| root.join(f).display().to_string())
+                    .collect(),
+            })
+            .collect();
+        println!("{}", serde_json::to_string(&list)?);
+        return Ok(());
+    }
+
+    for package in packages.iter() {
+        for file in package.files.iter() {
+            println!("{} {}", package.name, root.join(file).display());
+        }
+    }
+
+    Ok(())
+}
+
+fn owns(
+    root: &Path,
+    installed: &[InstalledPackage],
+    paths: &[&str],
+    json: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut results = Vec::new();
+    let mut unowned = Vec::new();
+
+    for path in paths.iter() {
+        let relative = relative_path(root, path)?;
+        let dir = format!("{}/", relative);
+
+        // directories can be shared by any number of packages
+        let owners: Vec<&InstalledPackage> = installed
+            .iter()
+            .filter(|p| p.files.iter().any(|f| f == &relative || f == &dir))
+            .collect();
+
+        if owners.is_empty() {
+            unowned.push(path.to_string());
+        } else if !json {
+            for owner in owners.iter() {
+                println!(
+                    "{} is owned by {} {}",
+                    root.join(&relative).display(),
+                    owner.name,
+                    owner.full_version()
+                );
+            }
+        }
+
+        results.push(PathOwners {
+            path: root.join(&relative).display().to_string(),
+            owners: owners.iter().map(|o| o.name.as_str()).collect(),
+        });
+    }
+
+    if json {
+        println!("{}", serde_json::to_string(&results)?);
+    }
+
+    if !unowned.is_empty() {
+        return Err(format!("no package owns {}", unowned.join(", ")).into());
+    }
+
+    Ok(())
+}
+
+/// Works out where a path given on the command line lives relative to the
+/// root, which is how the database refers to files.
+fn relative_path(root: &Path, path: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let root = absolute_path(root)?;
+    let path = absolute_path(Path::new(path))?;
+
+    let relative = match path.strip_prefix(&root) {
+        Ok(relative) => relative,
+        Err(_) => Err(format!("{} is not in {}", path.display(), root.display()))?,
+    };
+
+    match relative.to_str() {
+        Some(relative) => Ok(relative.to_string()),
+        None => Err(format!("{} is not a valid path", path.display()))?,
+    }
+}
+
+/// Makes the path absolute against the current directory, resolving `.` and
+/// `..` ourselves rather than canonicalizing so that we don't follow symlinks
+/// or require the file to still exist.
+fn absolute_path(path: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()?.join(path)
+    };
+
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => continue,
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// The names of the installed packages that depend on the package, either on
+/// it directly or on something that it provides.
+fn required_by(
+    installed: &[InstalledPackage],
+    package: &InstalledPackage,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut required_by = Vec::new();
+
+    for other in installed.iter() {
+        if other.name == package.name {
+            continue;
+        }
+
+        if depends::parse_all(&other.depends)?
+            .iter()
+            .any(|d| d.satisfied_by_package(package))
+        {
+            required_by.push(other.name.clone());
+        }
+    }
+
+    Ok(required_by)
+}
+
+/// Packages that were only installed as dependencies and that nothing needs
+/// any more.
+fn orphans(
+    installed: &[InstalledPackage],
+) -> Result<Vec<&InstalledPackage>, Box<dyn std::error::Error>> {
+    let mut orphans = Vec::new();
+
+    for package in installed.iter() {
+        if package.reason == InstallReason::Dependency
+            && required_by(installed, package)?.is_empty()
+        {
+            orphans.push(package);
+        }
+    }
+
+    Ok(orphans)
+}
+
+/// Formats a unix timestamp as a UTC date and time.
+fn format_date(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // days since the epoch to a civil date, from Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_package(name: &str, reason: InstallReason, depends: &[&str]) -> InstalledPackage {
+        InstalledPackage {
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+            reason,
+            ..InstalledPackage::for_test(name, "1.0")
+        }
+    }
+
+    #[test]
+    fn test_orphans() {
+        let mut gawk = test_package("gawk", InstallReason::Dependency, &[]);
+        gawk.provides = vec![String::from("awk")];
+        let installed = vec![
+            test_package("app", InstallReason::Explicit, &["libfoo>=1.0", "awk"]),
+            gawk,
+            test_package("libbar", InstallReason::Dependency, &[]),
+            test_package("libfoo", InstallReason::Dependency, &[]),
+            test_package("tool", InstallReason::Explicit, &[]),
+        ];
+
+        assert_eq!(required_by(&installed, &installed[1]).unwrap(), vec!["app"]);
+        assert!(required_by(&installed, &installed[0]).unwrap().is_empty());
+
+        let orphans: Vec<&str> = orphans(&installed)
+            .unwrap()
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(orphans, vec!["libbar"]);
+    }
+
+    #[test]
+    fn test_relative_path() {
+        let root = Path::new("/srv/root");
+        assert_eq!(
+            relative_path(root, "/srv/root/usr/bin/foo").unwrap(),
+            "usr/bin/foo"
+        );
+        assert_eq!(
+            relative_path(root, "/srv/root/usr/./lib/../bin/foo").unwrap(),
+            "usr/bin/foo"
+        );
+        assert!(relative_path(root, "/usr/bin/foo").is_err());
+        assert_eq!(
+            relative_path(Path::new("/"), "/etc/foo.conf").unwrap(),
+            "etc/foo.conf"
+        );
+
+        // a relative root is relative to the current directory too
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(
+            relative_path(Path::new("./chroot"), "chroot/usr/bin/foo").unwrap(),
+            "usr/bin/foo"
+        );
+        assert_eq!(
+            relative_path(
+                Path::new("chroot"),
+                cwd.join("chroot/etc").to_str().unwrap()
+            )
+            .unwrap(),
+            "etc"
+        );
+        assert!(relative_path(Path::new("chroot"), "/chroot/etc").is_err());
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_date(951782400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_date(1638316800 + 3723), "2021-12-01 01:02:03 UTC");
+    }
+}
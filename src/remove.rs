@@ -0,0 +1,338 @@
+use std::fs;
+
+use clap::ArgMatches;
+
+use super::db::{self, InstalledPackage};
+use super::depends;
+use super::downloader;
+use super::package::scriptlet::Hook;
+use super::transaction::Transaction;
+
+pub fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let (root, dbpath) = db::paths(cli);
+    let mut txn = Transaction::begin(&root, &dbpath)?;
+
+    let targets: Vec<&str> = cli.values_of("package").unwrap().collect();
+    let installed = txn.localdb().packages()?;
+    let removals = removal_set(
+        &installed,
+        &targets,
+        cli.is_present("cascade"),
+        cli.is_present("nodeps"),
+    )?;
+
+    for name in removals.iter() {
+        let package = installed.iter().find(|p| &p.name == name).unwrap();
+        remove_package(&mut txn, package)?;
+    }
+
+    txn.commit()
+}
+
+/// Works out which packages need to be removed, in the order that they should
+/// be removed in (packages before the packages that they depend on).
+fn removal_set(
+    installed: &[InstalledPackage],
+    targets: &[&str],
+    cascade: bool,
+    nodeps: bool,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut removals: Vec<String> = Vec::new();
+
+    for target in targets.iter() {
+        if !installed.iter().any(|p| &p.name == target) {
+            return Err(format!("{} is not installed", target).into());
+        }
+
+        if !removals.iter().any(|r| r == target) {
+            removals.push(target.to_string());
+        }
+    }
+
+    if nodeps {
+        return Ok(removals);
+    }
+
+    // keep going until nothing that's left depends on anything that we're
+    // removing, pulling in the dependents as we go if we're cascading
+    loop {
+        let mut required = Vec::new();
+
+        for package in installed.iter() {
+            if removals.contains(&package.name) {
+                continue;
+            }
+
+            // a dependency only holds us back if something we're removing
+            // satisfies it and nothing that's staying behind does
+            for depend in depends::parse_all(&package.depends)?.into_iter() {
+                let (removed, remaining): (Vec<_>, Vec<_>) = installed
+                    .iter()
+                    .filter(|p| depend.satisfied_by_package(*p))
+                    .partition(|p| removals.contains(&p.name));
+
+                if remaining.is_empty() {
+                    if let Some(removed) = removed.first() {
+                        required.push((package.name.clone(), removed.name.clone()));
+                    }
+                }
+            }
+        }
+
+        if required.is_empty() {
+            break;
+        }
+
+        if !cascade {
+            let reasons: Vec<String> = required
+                .iter()
+                .map(|(package, depend)| format!("{} is required by {}", depend, package))
+                .collect();
+            return Err(format!("unable to remove packages:\n  {}", reasons.join("\n  ")).into());
+        }
+
+        for (package, _) in required.into_iter() {
+            if !removals.contains(&package) {
+                removals.push(package);
+            }
+        }
+    }
+
+    // dependents were appended after the packages that they depend on
+    removals.reverse();
+    Ok(removals)
+}
+
+pub fn remove_package(
+    txn: &mut Transaction,
+    package: &InstalledPackage,
+) -> Result<(), Box<dyn std::error::Error>> {
+    println!("removing {} {}", package.name, package.full_version());
+
+    let version = package.full_version();
+    let scriptlets = txn.localdb().scriptlets(&package.name)?;
+    if let Some(scriptlets) = &scriptlets {
+        scriptlets.run(
+            Hook::PreRemove,
+            txn.root(),
+            &package.name,
+            Some(&version),
+            None,
+        )?;
+    }
+
+    let owners = txn.localdb().file_owners()?;
+    let mut dirs = Vec::new();
+
+    for file in package.files.iter() {
+        if file.ends_with('/') {
+            dirs.push(file);
+            continue;
+        }
+
+        if fs::symlink_metadata(txn.root().join(file)).is_ok() {
+            remove_file(txn, package, file)?;
+        }
+    }
+
+    // remove the deepest directories first so that their parents can become
+    // empty too, but leave anything still in use by other packages
+    dirs.sort_by(|a, b| b.cmp(a));
+    for dir in dirs.into_iter() {
+        if owners
+            .get(dir)
+            .is_some_and(|owners| owners.iter().any(|owner| owner != &package.name))
+        {
+            continue;
+        }
+
+        let path = txn.root().join(dir);
+        if let Ok(mut entries) = fs::read_dir(&path) {
+            if entries.next().is_none() {
+                txn.remove_dir(dir)?;
+            }
+        }
+    }
+
+    txn.remove_package(&package.name)?;
+
+    if let Some(scriptlets) = &scriptlets {
+        if let Err(err) = scriptlets.run(
+            Hook::PostRemove,
+            txn.root(),
+            &package.name,
+            Some(&version),
+            None,
+        ) {
+            eprintln!("warning: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes one of the package's files, unless it's one that the package
+/// marked for backup and an admin has changed since it was installed, in which
+/// case it's moved out of the way instead so that their work isn't lost.
+pub fn remove_file(
+    txn: &mut Transaction,
+    package: &InstalledPackage,
+    file: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let path = txn.root().join(file);
+
+    if let Some(expected) = package.backup.get(file) {
+        if path.is_file() && &downloader::file_sha256sum(path.to_str().unwrap())? != expected {
+            let save = format!("{}.mpmsave", file);
+            println!(
+                "warning: {} saved as {}",
+                file,
+                txn.root().join(&save).display()
+            );
+            return txn.rename(file, &save);
+        }
+    }
+
+    txn.remove_file(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::db::LocalDatabase;
+
+    fn test_package(name: &str, depends: &[&str], files: &[&str]) -> InstalledPackage {
+        InstalledPackage {
+            depends: depends.iter().map(|d| d.to_string()).collect(),
+            files: files.iter().map(|f| f.to_string()).collect(),
+            ..InstalledPackage::for_test(name, "1.0")
+        }
+    }
+
+    fn test_root(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mpm-remove-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        path
+    }
+
+    #[test]
+    fn test_removal_set() {
+        let installed = vec![
+            test_package("foo", &[], &[]),
+            test_package("bar", &["foo>=1.0"], &[]),
+            test_package("baz", &["bar"], &[]),
+            test_package("qux", &[], &[]),
+        ];
+
+        assert!(removal_set(&installed, &["foo"], false, false).is_err());
+        assert!(removal_set(&installed, &["missing"], false, true).is_err());
+        assert_eq!(
+            removal_set(&installed, &["foo"], false, true).unwrap(),
+            vec!["foo"]
+        );
+        assert_eq!(
+            removal_set(&installed, &["baz", "qux"], false, false).unwrap(),
+            vec!["qux", "baz"]
+        );
+        assert_eq!(
+            removal_set(&installed, &["foo"], true, false).unwrap(),
+            vec!["baz", "bar", "foo"]
+        );
+    }
+
+    #[test]
+    fn test_removal_set_provides() {
+        let mut gawk = test_package("gawk", &[], &[]);
+        gawk.provides = vec![String::from("awk")];
+        let mut mawk = test_package("mawk", &[], &[]);
+        mawk.provides = vec![String::from("awk")];
+
+        let installed = vec![gawk, mawk, test_package("foo", &["awk"], &[])];
+
+        assert_eq!(
+            removal_set(&installed, &["gawk"], false, false).unwrap(),
+            vec!["gawk"]
+        );
+        assert!(removal_set(&installed, &["gawk", "mawk"], false, false).is_err());
+        assert_eq!(
+            removal_set(&installed, &["gawk", "mawk"], true, false).unwrap(),
+            vec!["foo", "mawk", "gawk"]
+        );
+    }
+
+    #[test]
+    fn test_remove_package() {
+        let root = test_root("package");
+        let dbpath = root.join("var/lib/mpm");
+        let localdb = LocalDatabase::open(&dbpath).unwrap();
+
+        fs::create_dir_all(root.join("etc/foo")).unwrap();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::create_dir_all(root.join("usr/share/empty")).unwrap();
+        fs::write(root.join("etc/foo/foo.conf"), "modified").unwrap();
+        fs::write(root.join("etc/foo/other.conf"), "original").unwrap();
+        fs::write(root.join("usr/bin/foo"), "binary").unwrap();
+        fs::write(root.join("usr/bin/bar"), "binary").unwrap();
+
+        fs::write(root.join("etc/foo/defaults"), "modified").unwrap();
+
+        // only files marked for backup are worth saving
+        let mut package = test_package(
+            "foo",
+            &[],
+            &[
+                "etc/",
+                "etc/foo/",
+                "etc/foo/defaults",
+                "etc/foo/foo.conf",
+                "etc/foo/other.conf",
+                "usr/",
+                "usr/bin/",
+                "usr/bin/foo",
+                "usr/share/",
+                "usr/share/empty/",
+            ],
+        );
+        let original =
+            downloader::file_sha256sum(root.join("etc/foo/other.conf").to_str().unwrap()).unwrap();
+        for file in ["etc/foo/foo.conf", "etc/foo/other.conf"] {
+            package.backup.insert(file.to_string(), original.clone());
+        }
+        localdb.add(&package).unwrap();
+        localdb
+            .add(&test_package(
+                "bar",
+                &[],
+                &[
+                    "usr/",
+                    "usr/bin/",
+                    "usr/bin/bar",
+                    "usr/share/",
+                    "usr/share/empty/",
+                ],
+            ))
+            .unwrap();
+
+        let mut txn = Transaction::begin(&root, &dbpath).unwrap();
+        remove_package(&mut txn, &package).unwrap();
+        txn.commit().unwrap();
+
+        assert!(!root.join("usr/bin/foo").exists());
+        assert!(root.join("usr/bin/bar").exists());
+        // empty directories are left alone as long as anything else has them
+        assert!(root.join("usr/share/empty").is_dir());
+        assert!(!root.join("etc/foo/other.conf").exists());
+        assert!(!root.join("etc/foo/foo.conf").exists());
+        assert!(root.join("etc/foo/foo.conf.mpmsave").exists());
+        assert!(!root.join("etc/foo/defaults").exists());
+        assert!(!root.join("etc/foo/defaults.mpmsave").exists());
+        assert!(localdb.get("foo").unwrap().is_none());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
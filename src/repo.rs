@@ -0,0 +1,353 @@
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+
+use clap::ArgMatches;
+use compress_tools::{ArchiveContents, ArchiveIterator};
+use serde::{Deserialize, Serialize};
+use subprocess::{Exec, Redirection};
+
+use super::downloader;
+use super::package::pkginfo::PackageInfo;
+use super::version::Version;
+
+static DESC_FILE: &str = "desc";
+
+/// A single package in a repository database: its metadata from `.PKGINFO`
+/// plus everything needed to download and check the archive itself.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RepoPackage {
+    pub filename: String,
+    pub name: String,
+    pub base: String,
+    pub epoch: Option<u32>,
+    pub version: String,
+    pub release: u32,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    pub arch: String,
+    pub builddate: u64,
+    pub size: u64,
+    pub installed_size: u64,
+    pub sha256sum: String,
+    #[serde(default)]
+    pub license: Vec<String>,
+    #[serde(default)]
+    pub depends: Vec<String>,
+    #[serde(default)]
+    pub provides: Vec<String>,
+    #[serde(default)]
+    pub conflicts: Vec<String>,
+    #[serde(default)]
+    pub replaces: Vec<String>,
+}
+
+impl RepoPackage {
+    pub fn from_archive(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let info = PackageInfo::from_archive(path)?;
+        let filename = match Path::new(path).file_name() {
+            Some(filename) => filename.to_str().unwrap().to_string(),
+            None => return Err(format!("invalid package path {}", path).into()),
+        };
+
+        Ok(RepoPackage {
+            filename,
+            name: info.name,
+            base: info.base,
+            epoch: info.epoch,
+            version: info.version,
+            release: info.release,
+            description: info.description,
+            url: info.url,
+            arch: info.arch,
+            builddate: info.builddate,
+            size: fs::metadata(path)?.len(),
+            installed_size: info.size,
+            sha256sum: downloader::file_sha256sum(path)?,
+            license: info.license,
+            depends: info.depends,
+            provides: info.provides,
+            conflicts: info.conflicts,
+            replaces: info.replaces,
+        })
+    }
+
+    pub fn pkgver(&self) -> Version {
+        Version::new(self.epoch, &self.version, self.release)
+    }
+
+    /// The package metadata as it appears in the archive's `.PKGINFO`, which
+    /// is all that dependency resolution needs to know about.
+    pub fn info(&self) -> PackageInfo {
+        PackageInfo {
+            name: self.name.clone(),
+            base: self.base.clone(),
+            epoch: self.epoch,
+            version: self.version.clone(),
+            release: self.release,
+            description: self.description.clone(),
+            url: self.url.clone(),
+            arch: self.arch.clone(),
+            builddate: self.builddate,
+            size: self.installed_size,
+            license: self.license.clone(),
+            depends: self.depends.clone(),
+            provides: self.provides.clone(),
+            conflicts: self.conflicts.clone(),
+            replaces: self.replaces.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A repository of binary packages, stored as a gzipped tarball containing a
+/// `name-version/desc` entry for every package in it.
+#[derive(Debug, Default, PartialEq)]
+pub struct RepoDatabase {
+    pub packages: Vec<RepoPackage>,
+}
+
+impl RepoDatabase {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let source = File::open(path)?;
+        let entries = match ArchiveIterator::from_read(source) {
+            Ok(entries) => entries,
+            Err(err) => return Err(format!("unable to read {}: {}", path.display(), err))?,
+        };
+
+        // read every entry in a single pass, only keeping the contents of the
+        // desc files
+        let mut db = RepoDatabase::default();
+        let mut current: Option<(String, Vec<u8>)> = None;
+        for content in entries {
+            match content {
+                ArchiveContents::StartOfEntry(name) => {
+                    if name.ends_with(&format!("/{}", DESC_FILE)) {
+                        current = Some((name, Vec::new()));
+                    }
+                }
+                ArchiveContents::DataChunk(chunk) => {
+                    if let Some((_, contents)) = current.as_mut() {
+                        contents.extend(chunk);
+                    }
+                }
+                ArchiveContents::EndOfEntry => {
+                    if let Some((file, contents)) = current.take() {
+                        match serde_yaml::from_slice(&contents) {
+                            Ok(package) => db.packages.push(package),
+                            Err(err) => {
+                                return Err(format!(
+                                    "corrupt database entry {} in {}: {}",
+                                    file,
+                                    path.display(),
+                                    err
+                                ))?
+                            }
+                        }
+                    }
+                }
+                ArchiveContents::Err(err) => {
+                    return Err(format!("unable to read {}: {}", path.display(), err))?
+                }
+            }
+        }
+
+        db.packages.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(db)
+    }
+
+    /// Writes the database out as a new archive and then moves it over the
+    /// old one so that nobody downloading it ever sees a partial file.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let filename = path.file_name().unwrap().to_str().unwrap();
+        let staging = dir.join(format!(".{}.staging", filename));
+        let tmp = dir.join(format!(".{}.tmp", filename));
+
+        if staging.exists() {
+            fs::remove_dir_all(&staging)?;
+        }
+        fs::create_dir_all(&staging)?;
+
+        for package in self.packages.iter() {
+            let entry = staging.join(format!("{}-{}", package.name, package.pkgver()));
+            fs::create_dir(&entry)?;
+            fs::write(entry.join(DESC_FILE), serde_yaml::to_string(package)?)?;
+        }
+
+        let compress = Exec::cmd("bsdtar")
+            .arg("czf")
+            .arg(&tmp)
+            .arg("-C")
+            .arg(&staging)
+            .arg(".")
+            .stderr(Redirection::Merge)
+            .stdout(Redirection::Pipe)
+            .capture()?;
+
+        fs::remove_dir_all(&staging)?;
+
+        if !compress.success() {
+            return Err(format!(
+                "unable to write {}: {}",
+                path.display(),
+                compress.stdout_str().trim()
+            )
+            .into());
+        }
+
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Adds the package to the database, returning the entry that it replaced
+    /// if there was already a package with the same name.
+    pub fn add(&mut self, package: RepoPackage) -> Option<RepoPackage> {
+        let old = self.remove(&package.name);
+        self.packages.push(package);
+        self.packages.sort_by(|a, b| a.name.cmp(&b.name));
+        old
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<RepoPackage> {
+        let idx = self.packages.iter().position(|p| p.name == name)?;
+        Some(self.packages.remove(idx))
+    }
+}
+
+pub fn run_add(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(cli.value_of("database").unwrap());
+    let mut db = if path.exists() {
+        RepoDatabase::load(path)?
+    } else {
+        println!("creating {}", path.display());
+        RepoDatabase::default()
+    };
+
+    for archive in cli.values_of("package").unwrap() {
+        let package = RepoPackage::from_archive(archive)?;
+        let (name, version) = (package.name.clone(), package.pkgver());
+
+        match db.add(package) {
+            Some(old) => println!("updating {} ({} -> {})", name, old.pkgver(), version),
+            None => println!("adding {} {}", name, version),
+        }
+    }
+
+    db.save(path)
+}
+
+pub fn run_remove(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let path = Path::new(cli.value_of("database").unwrap());
+    let mut db = RepoDatabase::load(path)?;
+
+    for name in cli.values_of("package").unwrap() {
+        match db.remove(name) {
+            Some(old) => println!("removing {} {}", old.name, old.pkgver()),
+            None => return Err(format!("{} is not in {}", name, path.display()).into()),
+        }
+    }
+
+    db.save(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mpm-repo-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn build_package(dir: &Path, name: &str, version: &str) -> String {
+        let pkgdir = dir.join(format!("{}-pkg", name));
+        fs::create_dir_all(pkgdir.join("usr/bin")).unwrap();
+        fs::write(pkgdir.join("usr/bin").join(name), "binary").unwrap();
+        fs::write(
+            pkgdir.join(".PKGINFO"),
+            format!(
+                "pkgname = {}\npkgbase = {}\npkgver = {}-1\narch = any\nsize = 6\ndepend = bash\n",
+                name, name, version
+            ),
+        )
+        .unwrap();
+
+        let archive = dir.join(format!("{}-{}-1-any.pkg.tar.gz", name, version));
+        let status = Exec::cmd("bsdtar")
+            .arg("czf")
+            .arg(&archive)
+            .arg("-C")
+            .arg(&pkgdir)
+            .arg(".PKGINFO")
+            .arg("usr")
+            .join()
+            .unwrap();
+        assert!(status.success());
+
+        archive.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_from_archive() {
+        let dir = test_dir("archive");
+        let archive = build_package(&dir, "foo", "1.0");
+
+        let package = RepoPackage::from_archive(&archive).unwrap();
+        assert_eq!(package.filename, "foo-1.0-1-any.pkg.tar.gz");
+        assert_eq!(package.name, "foo");
+        assert_eq!(package.pkgver().to_string(), "1.0-1");
+        assert_eq!(package.installed_size, 6);
+        assert_eq!(package.size, fs::metadata(&archive).unwrap().len());
+        assert_eq!(
+            package.sha256sum,
+            downloader::file_sha256sum(&archive).unwrap()
+        );
+        assert_eq!(package.depends, vec!["bash"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = test_dir("database");
+        let path = dir.join("test.db.tar.gz");
+
+        let mut db = RepoDatabase::default();
+        db.save(&path).unwrap();
+        assert_eq!(RepoDatabase::load(&path).unwrap(), db);
+
+        let foo = RepoPackage::from_archive(&build_package(&dir, "foo", "1.0")).unwrap();
+        let bar = RepoPackage::from_archive(&build_package(&dir, "bar", "1.0")).unwrap();
+        assert!(db.add(foo).is_none());
+        assert!(db.add(bar).is_none());
+        db.save(&path).unwrap();
+
+        let mut loaded = RepoDatabase::load(&path).unwrap();
+        assert_eq!(loaded, db);
+        assert_eq!(loaded.packages[0].name, "bar");
+
+        let foo = RepoPackage::from_archive(&build_package(&dir, "foo", "1.1")).unwrap();
+        let old = loaded.add(foo).unwrap();
+        assert_eq!(old.version, "1.0");
+        assert!(loaded.remove("bar").is_some());
+        assert!(loaded.remove("bar").is_none());
+        loaded.save(&path).unwrap();
+
+        let loaded = RepoDatabase::load(&path).unwrap();
+        assert_eq!(loaded.packages.len(), 1);
+        assert_eq!(loaded.packages[0].version, "1.1");
+        assert!(!dir.join(".test.db.tar.gz.tmp").exists());
+        assert!(!dir.join(".test.db.tar.gz.staging").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
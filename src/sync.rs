@@ -0,0 +1,308 @@
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use indicatif::ProgressBar;
+use reqwest::Client;
+use serde::Deserialize;
+
+use super::config::{self, Config};
+use super::db;
+use super::downloader::{self, Validators};
+use super::repo::RepoDatabase;
+
+static SYNCDB_DIR: &str = "sync";
+
+/// A repository of binary packages along with the mirrors that serve it, in
+/// the order that they should be tried.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Repository {
+    pub name: String,
+    pub mirrors: Vec<String>,
+}
+
+impl Repository {
+    /// Loads a list of repositories on its own, the way they were configured
+    /// before they moved into the config file.
+    pub fn load_all(path: &Path) -> Result<Vec<Self>, Box<dyn std::error::Error>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("unable to read {}: {}", path.display(), err))?,
+        };
+
+        match serde_yaml::from_reader(file) {
+            Ok(repos) => Ok(repos),
+            Err(err) => Err(format!(
+                "invalid repository list {}: {}",
+                path.display(),
+                err
+            ))?,
+        }
+    }
+
+    fn database_url(&self, mirror: &str) -> String {
+        format!("{}/{}.db", mirror.trim_end_matches('/'), self.name)
+    }
+}
+
+/// Our copies of the repository databases, stored under `dbpath/sync` along
+/// with the validators needed to check whether they're still current.
+pub struct SyncDatabases {
+    path: PathBuf,
+}
+
+impl SyncDatabases {
+    pub fn open(dbpath: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = dbpath.join(SYNCDB_DIR);
+        fs::create_dir_all(&path)?;
+
+        Ok(SyncDatabases { path })
+    }
+
+    /// Downloads the database for the repository from the first mirror that
+    /// can provide it, returning `false` if ours was already up to date.
+    pub async fn sync(
+        &self,
+        client: &Client,
+        repo: &Repository,
+        force: bool,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
+        let dest = self.database_path(&repo.name);
+        let validators_path = self.path.join(format!("{}.db.validators", repo.name));
+        // downloaded here and only moved into place once we know it's valid
+        let staged = self.path.join(format!(".{}.db.new", repo.name));
+
+        let validators = if force || !dest.exists() || !validators_path.exists() {
+            Validators::default()
+        } else {
+            serde_yaml::from_reader(File::open(&validators_path)?).unwrap_or_default()
+        };
+
+        if repo.mirrors.is_empty() {
+            return Err(format!("no mirrors configured for {}", repo.name).into());
+        }
+
+        let mut errors = Vec::new();
+        for mirror in repo.mirrors.iter() {
+            let url = repo.database_url(mirror);
+
+            let pb = ProgressBar::new(0);
+            match downloader::download_file(client, &url, &staged, Some(&validators), None, &pb)
+                .await
+            {
+                Ok(None) => return Ok(false),
+                Ok(Some(validators)) => {
+                    pb.finish_with_message("Done");
+
+                    // don't throw away a working database for something that
+                    // we can't read
+                    if let Err(err) = RepoDatabase::load(&staged) {
+                        fs::remove_file(&staged)?;
+                        errors.push(format!("{}: {}", url, err));
+                        continue;
+                    }
+
+                    // stale validators must never be paired with the new
+                    // database or we'd skip the next real update
+                    if validators_path.exists() {
+                        fs::remove_file(&validators_path)?;
+                    }
+                    fs::rename(&staged, &dest)?;
+                    db::write_atomic(&validators_path, &serde_yaml::to_string(&validators)?)?;
+
+                    return Ok(true);
+                }
+                Err(err) => errors.push(format!("{}: {}", url, err)),
+            }
+        }
+
+        if staged.exists() {
+            fs::remove_file(&staged)?;
+        }
+
+        Err(format!("unable to sync {}:\n  {}", repo.name, errors.join("\n  ")).into())
+    }
+
+    pub fn load(&self, repo: &str) -> Result<RepoDatabase, Box<dyn std::error::Error>> {
+        let path = self.database_path(repo);
+        if !path.exists() {
+            return Err(format!("no database for {}, run mpm sync first", repo).into());
+        }
+
+        RepoDatabase::load(&path)
+    }
+
+    fn database_path(&self, repo: &str) -> PathBuf {
+        self.path.join(format!("{}.db", repo))
+    }
+}
+
+pub async fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load(cli)?;
+    if config.repos().is_empty() {
+        return Err(format!(
+            "no repositories are configured, add them to the repos section of {}",
+            config::CONFIG_FILE
+        )
+        .into());
+    }
+
+    let (_, dbpath) = db::paths(cli);
+    let syncdbs = SyncDatabases::open(&dbpath)?;
+    let client = config.client()?;
+
+    let mut failed = Vec::new();
+    for repo in config.repos().iter() {
+        println!("syncing {}", repo.name);
+
+        match syncdbs.sync(&client, repo, cli.is_present("force")).await {
+            Ok(true) => (),
+            Ok(false) => println!("{} is up to date", repo.name),
+            Err(err) => {
+                eprintln!("error: {}", err);
+                failed.push(repo.name.as_str());
+            }
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(format!("failed to sync {}", failed.join(", ")).into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::repo::RepoPackage;
+    use crate::test_server::{Response, TestServer};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mpm-sync-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn test_database(dir: &Path) -> (RepoDatabase, Vec<u8>) {
+        let mut db = RepoDatabase::default();
+        db.add(RepoPackage {
+            filename: String::from("foo-1.0-1-any.pkg.tar.gz"),
+            name: String::from("foo"),
+            base: String::from("foo"),
+            epoch: None,
+            version: String::from("1.0"),
+            release: 1,
+            description: None,
+            url: None,
+            arch: String::from("any"),
+            builddate: 0,
+            size: 100,
+            installed_size: 200,
+            sha256sum: String::from("abc123"),
+            license: Vec::new(),
+            depends: Vec::new(),
+            provides: Vec::new(),
+            conflicts: Vec::new(),
+            replaces: Vec::new(),
+        });
+
+        let path = dir.join("core.db");
+        db.save(&path).unwrap();
+        let contents = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        (db, contents)
+    }
+
+    fn test_client() -> Client {
+        Client::builder().no_proxy().build().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_sync_not_modified() {
+        let dir = test_dir("modified");
+        let (db, contents) = test_database(&dir);
+
+        let server = TestServer::start(move |request| {
+            if request.path != "/core.db" {
+                return Response::new(404, b"");
+            }
+            if request.headers.get("if-none-match").map(|e| e.as_str()) == Some("\"v1\"") {
+                return Response::new(304, b"");
+            }
+
+            Response::new(200, &contents)
+                .header("ETag", "\"v1\"")
+                .header("Last-Modified", "Wed, 21 Oct 2015 07:28:00 GMT")
+        });
+
+        let repo = Repository {
+            name: String::from("core"),
+            mirrors: vec![format!("{}/", server.url)],
+        };
+        let syncdbs = SyncDatabases::open(&dir).unwrap();
+        let client = test_client();
+
+        assert!(syncdbs.sync(&client, &repo, false).await.unwrap());
+        assert_eq!(RepoDatabase::load(&dir.join("sync/core.db")).unwrap(), db);
+        assert!(!syncdbs.sync(&client, &repo, false).await.unwrap());
+        assert!(syncdbs.sync(&client, &repo, true).await.unwrap());
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 3);
+        assert!(!requests[0].headers.contains_key("if-none-match"));
+        assert_eq!(requests[1].headers["if-none-match"], "\"v1\"");
+        assert_eq!(
+            requests[1].headers["if-modified-since"],
+            "Wed, 21 Oct 2015 07:28:00 GMT"
+        );
+        assert!(!requests[2].headers.contains_key("if-none-match"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_mirrors() {
+        let dir = test_dir("mirrors");
+        let (db, contents) = test_database(&dir);
+
+        let broken = TestServer::start(|_| Response::new(404, b"not found"));
+        let working = TestServer::start(move |_| Response::new(200, &contents));
+
+        let syncdbs = SyncDatabases::open(&dir).unwrap();
+        let client = test_client();
+
+        let repo = Repository {
+            name: String::from("core"),
+            mirrors: vec![broken.url.clone(), working.url.clone()],
+        };
+        assert!(syncdbs.sync(&client, &repo, false).await.unwrap());
+        assert_eq!(RepoDatabase::load(&dir.join("sync/core.db")).unwrap(), db);
+
+        // without any validators we always have to download it again
+        assert!(syncdbs.sync(&client, &repo, false).await.unwrap());
+
+        let repo = Repository {
+            name: String::from("extra"),
+            mirrors: vec![broken.url.clone(), broken.url.clone()],
+        };
+        let err = syncdbs
+            .sync(&client, &repo, false)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("unable to sync extra:"));
+        assert_eq!(err.matches("404 Not Found").count(), 2);
+        assert!(!dir.join("sync/extra.db").exists());
+        assert!(!dir.join("sync/.extra.db.new").exists());
+        assert!(!dir.join("sync/.extra.db.new.part").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,574 @@
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::os::unix::fs::{self as unix_fs, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+
+use super::db::{InstalledPackage, LocalDatabase};
+use super::package::mtree::Mtree;
+use super::package::scriptlet::Scriptlets;
+
+static LOCK_FILE: &str = "db.lck";
+static CACHE_LOCK_FILE: &str = "cache.lck";
+static TRANSACTION_DIR: &str = "transaction";
+
+/// A single change made by a transaction with everything needed to undo it.
+enum Change {
+    CreatedDir(PathBuf),
+    RemovedDir {
+        path: PathBuf,
+        mode: u32,
+        uid: u32,
+        gid: u32,
+    },
+    Installed {
+        path: PathBuf,
+        backup: Option<PathBuf>,
+    },
+    Removed {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    Renamed {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Database {
+        name: String,
+        backup: Option<PathBuf>,
+    },
+}
+
+/// Holds a lock, on the database or on a package cache, for as long as it's
+/// alive.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    fn acquire(path: &Path, what: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(mut file) => {
+                writeln!(file, "{}", process::id())?;
+                Ok(Lock {
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Err(format!(
+                "unable to lock {}: {} exists\n  \
+                 if you're sure that mpm isn't already running you can remove it",
+                what,
+                path.display()
+            ))?,
+            Err(err) => Err(format!(
+                "unable to lock {} {}: {}",
+                what,
+                path.display(),
+                err
+            ))?,
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Locks the package cache while packages are downloaded into it or cleaned
+/// out of it. This is separate from the database lock so that upgrades don't
+/// have to hold that one while they're waiting on the network.
+pub fn lock_cache(cache: &Path) -> Result<Lock, Box<dyn std::error::Error>> {
+    fs::create_dir_all(cache)?;
+    Lock::acquire(&cache.join(CACHE_LOCK_FILE), "package cache")
+}
+
+/// A set of changes to the filesystem and the local database that either all
+/// happen or all get undone. Anything replaced or removed is only moved aside
+/// until the transaction is committed, and dropping a transaction without
+/// committing it (e.g., by returning an error) rolls everything back.
+pub struct Transaction {
+    root: PathBuf,
+    localdb: LocalDatabase,
+    dir: PathBuf,
+    changes: Vec<Change>,
+    backups: usize,
+    committed: bool,
+    _lock: Lock,
+}
+
+impl Transaction {
+    pub fn begin(root: &Path, dbpath: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        fs::create_dir_all(dbpath)?;
+        let lock = Lock::acquire(&dbpath.join(LOCK_FILE), "database")?;
+        let localdb = LocalDatabase::create(dbpath)?;
+
+        // if we were killed part way through a transaction then the backups
+        // are the only copy of whatever we replaced so leave them alone
+        let dir = dbpath.join(TRANSACTION_DIR);
+        if dir.exists() {
+            return Err(format!(
+                "found an interrupted transaction in {}, restore or remove it before continuing",
+                dir.display()
+            )
+            .into());
+        }
+        fs::create_dir_all(dir.join("backup"))?;
+        fs::create_dir_all(dir.join("stage"))?;
+
+        Ok(Transaction {
+            root: root.to_path_buf(),
+            localdb,
+            dir,
+            changes: Vec::new(),
+            backups: 0,
+            committed: false,
+            _lock: lock,
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn localdb(&self) -> &LocalDatabase {
+        &self.localdb
+    }
+
+    /// Returns an empty directory to extract a package into before its files
+    /// get moved into place.
+    pub fn staging_dir(&self, name: &str) -> Result<PathBuf, Box<dyn std::error::Error>> {
+        let path = self.dir.join("stage").join(name);
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        fs::create_dir_all(&path)?;
+
+        Ok(path)
+    }
+
+    /// Creates the directory (relative to the root) with the same permissions
+    /// and ownership as `meta` unless something is already there.
+    pub fn create_dir(
+        &mut self,
+        path: &str,
+        meta: &fs::Metadata,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let target = self.root.join(path);
+        self.create_parents(&target)?;
+
+        if fs::symlink_metadata(&target).is_ok() {
+            return Ok(());
+        }
+
+        fs::create_dir(&target)?;
+        self.changes.push(Change::CreatedDir(target.clone()));
+        unix_fs::chown(&target, Some(meta.uid()), Some(meta.gid()))?;
+        fs::set_permissions(&target, fs::Permissions::from_mode(meta.mode() & 0o7777))?;
+
+        Ok(())
+    }
+
+    /// Moves a staged file into place at `path` (relative to the root),
+    /// holding on to whatever it replaces.
+    pub fn install_file(
+        &mut self,
+        staged: &Path,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let target = self.root.join(path);
+        self.create_parents(&target)?;
+
+        let backup = match fs::symlink_metadata(&target) {
+            Ok(meta) if meta.is_dir() => {
+                return Err(
+                    format!("unable to install {}: it's a directory", target.display()).into(),
+                )
+            }
+            Ok(_) => {
+                let backup = self.next_backup();
+                move_path(&target, &backup)?;
+                Some(backup)
+            }
+            Err(_) => None,
+        };
+
+        // record it before moving anything so that the backup is restored
+        // even if the move fails
+        self.changes.push(Change::Installed {
+            path: target.clone(),
+            backup,
+        });
+        move_path(staged, &target)?;
+
+        Ok(())
+    }
+
+    /// Removes the file at `path` (relative to the root), holding on to it
+    /// until the transaction is committed.
+    pub fn remove_file(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let target = self.root.join(path);
+        let backup = self.next_backup();
+
+        move_path(&target, &backup)?;
+        self.changes.push(Change::Removed {
+            path: target,
+            backup,
+        });
+
+        Ok(())
+    }
+
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let target = self.root.join(path);
+        let meta = fs::symlink_metadata(&target)?;
+
+        fs::remove_dir(&target)?;
+        self.changes.push(Change::RemovedDir {
+            path: target,
+            mode: meta.mode() & 0o7777,
+            uid: meta.uid(),
+            gid: meta.gid(),
+        });
+
+        Ok(())
+    }
+
+    /// Renames `from` to `to` (both relative to the root), replacing anything
+    /// that's already at `to`.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if fs::symlink_metadata(self.root.join(to)).is_ok() {
+            self.remove_file(to)?;
+        }
+
+        let (from, to) = (self.root.join(from), self.root.join(to));
+        fs::rename(&from, &to)?;
+        self.changes.push(Change::Renamed { from, to });
+
+        Ok(())
+    }
+
+    /// Records the package, its file manifest and its scriptlets in the local
+    /// database.
+    pub fn add_package(
+        &mut self,
+        package: &InstalledPackage,
+        mtree: &Mtree,
+        scriptlets: Option<&Scriptlets>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.save_entry(&package.name)?;
+        self.localdb.add(package)?;
+        self.localdb.set_mtree(&package.name, mtree)?;
+        self.localdb.set_scriptlets(&package.name, scriptlets)
+    }
+
+    pub fn remove_package(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.save_entry(name)?;
+        self.localdb.remove(name)
+    }
+
+    pub fn commit(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.committed = true;
+        fs::remove_dir_all(&self.dir)?;
+        Ok(())
+    }
+
+    /// Creates any missing parent directories of `target`.
+    fn create_parents(&mut self, target: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let mut missing = Vec::new();
+        let mut parent = target.parent();
+        while let Some(dir) = parent {
+            if dir.exists() {
+                break;
+            }
+            missing.push(dir.to_path_buf());
+            parent = dir.parent();
+        }
+
+        for dir in missing.into_iter().rev() {
+            fs::create_dir(&dir)?;
+            self.changes.push(Change::CreatedDir(dir));
+        }
+
+        Ok(())
+    }
+
+    /// Keeps a copy of the package's database entry, as it was before the
+    /// transaction first touched it, to restore on rollback.
+    fn save_entry(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let saved = self
+            .changes
+            .iter()
+            .any(|c| matches!(c, Change::Database { name: n, .. } if n == name));
+        if saved {
+            return Ok(());
+        }
+
+        let backup = self.dir.join("db").join(name);
+        let backup = if self.localdb.backup(name, &backup)? {
+            Some(backup)
+        } else {
+            None
+        };
+
+        self.changes.push(Change::Database {
+            name: name.to_string(),
+            backup,
+        });
+
+        Ok(())
+    }
+
+    fn next_backup(&mut self) -> PathBuf {
+        self.backups += 1;
+        self.dir.join("backup").join(self.backups.to_string())
+    }
+
+    fn undo(&self, change: &Change) -> Result<(), Box<dyn std::error::Error>> {
+        match change {
+            Change::CreatedDir(path) => match fs::remove_dir(path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err)?,
+                _ => (),
+            },
+            Change::RemovedDir {
+                path,
+                mode,
+                uid,
+                gid,
+            } => {
+                fs::create_dir(path)?;
+                unix_fs::chown(path, Some(*uid), Some(*gid))?;
+                fs::set_permissions(path, fs::Permissions::from_mode(*mode))?;
+            }
+            Change::Installed { path, backup } => {
+                if fs::symlink_metadata(path).is_ok() {
+                    fs::remove_file(path)?;
+                }
+                if let Some(backup) = backup {
+                    move_path(backup, path)?;
+                }
+            }
+            Change::Removed { path, backup } => move_path(backup, path)?,
+            Change::Renamed { from, to } => fs::rename(to, from)?,
+            Change::Database { name, backup } => self.localdb.restore(name, backup.as_deref())?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        if self.committed {
+            return;
+        }
+
+        if !self.changes.is_empty() {
+            eprintln!("rolling back changes");
+        }
+
+        let mut errors = Vec::new();
+        while let Some(change) = self.changes.pop() {
+            if let Err(err) = self.undo(&change) {
+                errors.push(err.to_string());
+            }
+        }
+
+        if errors.is_empty() {
+            let _ = fs::remove_dir_all(&self.dir);
+        } else {
+            eprintln!(
+                "error: unable to roll back everything:\n  {}\nbackups have been kept in {}",
+                errors.join("\n  "),
+                self.dir.display()
+            );
+        }
+    }
+}
+
+/// Renames the file, falling back to copying it when the destination is on
+/// another filesystem.
+fn move_path(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(err) if err.kind() == io::ErrorKind::CrossesDevices => {
+            let meta = fs::symlink_metadata(from)?;
+            if meta.file_type().is_symlink() {
+                unix_fs::symlink(fs::read_link(from)?, to)?;
+            } else {
+                fs::copy(from, to)?;
+                unix_fs::chown(to, Some(meta.uid()), Some(meta.gid()))?;
+                fs::set_permissions(to, meta.permissions())?;
+            }
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_root(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mpm-transaction-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn test_package(name: &str, files: &[&str]) -> InstalledPackage {
+        InstalledPackage {
+            files: files.iter().map(|f| f.to_string()).collect(),
+            ..InstalledPackage::for_test(name, "1.0")
+        }
+    }
+
+    #[test]
+    fn test_lock() {
+        let root = test_root("lock");
+        let dbpath = root.join("var/lib/mpm");
+
+        let txn = Transaction::begin(&root, &dbpath).unwrap();
+        let err = Transaction::begin(&root, &dbpath).err().unwrap();
+        assert!(err.to_string().starts_with("unable to lock database"));
+        drop(txn);
+
+        let txn = Transaction::begin(&root, &dbpath).unwrap();
+        txn.commit().unwrap();
+        assert!(!dbpath.join(LOCK_FILE).exists());
+        assert!(!dbpath.join(TRANSACTION_DIR).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_lock_cache() {
+        let root = test_root("cache");
+        let cache = root.join("var/cache/mpm/pkg");
+
+        let lock = lock_cache(&cache).unwrap();
+        let err = lock_cache(&cache).err().unwrap();
+        assert!(err.to_string().starts_with("unable to lock package cache"));
+
+        // it doesn't get in the way of the database
+        Transaction::begin(&root, &root.join("var/lib/mpm"))
+            .unwrap()
+            .commit()
+            .unwrap();
+
+        drop(lock);
+        assert!(!cache.join(CACHE_LOCK_FILE).exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Sets up a root with an installed package "old" and returns a
+    /// transaction that has changed everything that it can.
+    fn make_changes(root: &Path) -> Transaction {
+        let dbpath = root.join("var/lib/mpm");
+        fs::create_dir_all(root.join("etc/old")).unwrap();
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::write(root.join("etc/old/old.conf"), "config").unwrap();
+        fs::write(root.join("usr/bin/old"), "old").unwrap();
+        fs::write(root.join("usr/bin/shared"), "old shared").unwrap();
+
+        let old = test_package(
+            "old",
+            &["etc/", "etc/old/", "etc/old/old.conf", "usr/bin/old"],
+        );
+        LocalDatabase::open(&dbpath).unwrap().add(&old).unwrap();
+
+        let mut txn = Transaction::begin(root, &dbpath).unwrap();
+
+        let stage = txn.staging_dir("new").unwrap();
+        fs::create_dir_all(stage.join("usr/share/new")).unwrap();
+        fs::write(stage.join("usr/share/new/data"), "data").unwrap();
+        fs::write(stage.join("shared"), "new shared").unwrap();
+
+        let meta = fs::metadata(stage.join("usr/share/new")).unwrap();
+        txn.create_dir("usr/share/new", &meta).unwrap();
+        txn.install_file(&stage.join("usr/share/new/data"), "usr/share/new/data")
+            .unwrap();
+        txn.install_file(&stage.join("shared"), "usr/bin/shared")
+            .unwrap();
+        txn.add_package(
+            &test_package("new", &["usr/share/new/data"]),
+            &Mtree::default(),
+            None,
+        )
+        .unwrap();
+
+        txn.remove_file("usr/bin/old").unwrap();
+        txn.rename("etc/old/old.conf", "etc/old/old.conf.mpmsave")
+            .unwrap();
+        txn.remove_package("old").unwrap();
+
+        txn
+    }
+
+    #[test]
+    fn test_commit() {
+        let root = test_root("commit");
+        let txn = make_changes(&root);
+        txn.commit().unwrap();
+
+        let localdb = LocalDatabase::open(&root.join("var/lib/mpm")).unwrap();
+        assert!(localdb.get("old").unwrap().is_none());
+        assert!(localdb.get("new").unwrap().is_some());
+        assert_eq!(fs::read(root.join("usr/share/new/data")).unwrap(), b"data");
+        assert_eq!(
+            fs::read(root.join("usr/bin/shared")).unwrap(),
+            b"new shared"
+        );
+        assert!(!root.join("usr/bin/old").exists());
+        assert!(root.join("etc/old/old.conf.mpmsave").exists());
+        assert!(!root.join("var/lib/mpm/transaction").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_rollback() {
+        let root = test_root("rollback");
+        drop(make_changes(&root));
+
+        let localdb = LocalDatabase::open(&root.join("var/lib/mpm")).unwrap();
+        assert!(localdb.get("old").unwrap().is_some());
+        assert!(localdb.get("new").unwrap().is_none());
+        assert!(!root.join("usr/share").exists());
+        assert_eq!(
+            fs::read(root.join("usr/bin/shared")).unwrap(),
+            b"old shared"
+        );
+        assert_eq!(fs::read(root.join("usr/bin/old")).unwrap(), b"old");
+        assert_eq!(fs::read(root.join("etc/old/old.conf")).unwrap(), b"config");
+        assert!(!root.join("etc/old/old.conf.mpmsave").exists());
+        assert!(!root.join("var/lib/mpm/transaction").exists());
+        assert!(!root.join("var/lib/mpm/db.lck").exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_rollback_removed_dir() {
+        let root = test_root("dir");
+        let dbpath = root.join("var/lib/mpm");
+        fs::create_dir_all(root.join("opt/empty")).unwrap();
+        fs::set_permissions(root.join("opt/empty"), fs::Permissions::from_mode(0o700)).unwrap();
+
+        let mut txn = Transaction::begin(&root, &dbpath).unwrap();
+        txn.remove_dir("opt/empty").unwrap();
+        assert!(!root.join("opt/empty").exists());
+        drop(txn);
+
+        let meta = fs::metadata(root.join("opt/empty")).unwrap();
+        assert_eq!(meta.mode() & 0o7777, 0o700);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
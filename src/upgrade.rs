@@ -1,4 +1,504 @@
-pub fn run() -> Result<(), Box<dyn std::error::Error>> {
-    println!("TODO: upgrade");
-    Ok(())
+use std::cmp::Ordering;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use clap::ArgMatches;
+use reqwest::Client;
+
+use super::config::Config;
+use super::db::{self, InstallReason, InstalledPackage, LocalDatabase};
+use super::depends::{self, Dependency, Operator, Resolver};
+use super::downloader::{self, Download, Retry};
+use super::install;
+use super::package::pkginfo::PackageInfo;
+use super::repo::{RepoDatabase, RepoPackage};
+use super::sync::{Repository, SyncDatabases};
+use super::transaction::{self, Transaction};
+
+pub async fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let config = Config::load(cli)?;
+    let (root, dbpath) = db::paths(cli);
+    let syncdbs = SyncDatabases::open(&dbpath)?;
+
+    let repos = config.repos();
+    let databases = repos
+        .iter()
+        .map(|repo| syncdbs.load(&repo.name))
+        .collect::<Result<Vec<RepoDatabase>, _>>()?;
+
+    // everything from every repository, in the order that the repositories
+    // were configured in
+    let available: Vec<(&Repository, &RepoPackage)> = repos
+        .iter()
+        .zip(databases.iter())
+        .flat_map(|(repo, db)| db.packages.iter().map(move |package| (repo, package)))
+        .collect();
+    let infos: Vec<PackageInfo> = available.iter().map(|(_, p)| p.info()).collect();
+
+    let installed = LocalDatabase::open(&dbpath)?.packages()?;
+    let targets: Vec<Dependency> = find_upgrades(&installed, &available, &config.ignored(cli))?
+        .into_iter()
+        .map(|idx| Dependency {
+            name: infos[idx].name.clone(),
+            constraint: Some((Operator::Eq, infos[idx].pkgver())),
+        })
+        .collect();
+
+    if targets.is_empty() {
+        println!("nothing to upgrade");
+        return Ok(());
+    }
+
+    let upgrades: Vec<(&Repository, &RepoPackage)> = Resolver::new(&infos, &installed)
+        .resolve(&targets)?
+        .into_iter()
+        .map(|info| {
+            let idx = infos.iter().position(|i| std::ptr::eq(i, info)).unwrap();
+            available[idx]
+        })
+        .collect();
+
+    print_summary(&installed, &upgrades);
+
+    if !cli.is_present("noconfirm") && !confirm("Proceed with upgrade?")? {
+        return Ok(());
+    }
+
+    // get everything onto disk and checked before we start changing the
+    // system so that a bad mirror can't leave us half upgraded
+    let cache = config.cache_dir(cli, &root);
+    let _cache_lock = transaction::lock_cache(&cache)?;
+
+    let client = config.client()?;
+    let archives = fetch_packages(
+        &client,
+        &cache,
+        &upgrades,
+        config.parallel_downloads,
+        &config.retry(),
+    )
+    .await?;
+
+    // only hold the lock while we're changing the system, not while we're
+    // waiting on the user or the network
+    let mut txn = Transaction::begin(&root, &dbpath)?;
+    if txn.localdb().packages()? != installed {
+        return Err("installed packages changed while downloading, run the upgrade again".into());
+    }
+
+    for ((_, package), archive) in upgrades.iter().zip(archives.iter()) {
+        let reason = install_reason(package, &targets, &installed);
+        install::install_package(&mut txn, archive.to_str().unwrap(), reason)?;
+    }
+
+    txn.commit()
+}
+
+/// Why a package that's part of the upgrade is installed. Upgrades keep the
+/// reason of what they upgrade or replace, anything new that they pull in is
+/// only there as a dependency.
+fn install_reason(
+    package: &RepoPackage,
+    targets: &[Dependency],
+    installed: &[InstalledPackage],
+) -> Option<InstallReason> {
+    if targets.iter().any(|target| target.name == package.name)
+        || installed.iter().any(|p| p.name == package.name)
+    {
+        None
+    } else {
+        Some(InstallReason::Dependency)
+    }
+}
+
+/// Works out which of the available packages should be installed to bring the
+/// system up to date, returning their indices. Packages that replace something
+/// that's installed take priority over newer versions of the package itself,
+/// and ignored packages are left as they are.
+fn find_upgrades(
+    installed: &[InstalledPackage],
+    available: &[(&Repository, &RepoPackage)],
+    ignored: &[String],
+) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+    let mut replaces = Vec::new();
+    for (_, package) in available.iter() {
+        replaces.push(depends::parse_all(&package.replaces)?);
+    }
+
+    let mut upgrades = Vec::new();
+    for package in installed.iter() {
+        let replacement = available.iter().enumerate().position(|(idx, (_, p))| {
+            p.name != package.name
+                && !installed.iter().any(|i| i.name == p.name)
+                && replaces[idx]
+                    .iter()
+                    .any(|r| r.satisfied_by(&package.name, &package.pkgver()))
+        });
+
+        if let Some(idx) = replacement {
+            if ignored.contains(&package.name) {
+                println!(
+                    "warning: {}: ignoring replacement by {}",
+                    package.name, available[idx].1.name
+                );
+                continue;
+            }
+            if !upgrades.contains(&idx) {
+                upgrades.push(idx);
+            }
+            continue;
+        }
+
+        // ties go to whichever repository was configured first
+        let mut newest: Option<usize> = None;
+        for (idx, (_, p)) in available.iter().enumerate() {
+            if p.name != package.name {
+                continue;
+            }
+
+            let newer = match newest {
+                Some(best) => p.pkgver().vercmp(&available[best].1.pkgver()) == Ordering::Greater,
+                None => true,
+            };
+            if newer {
+                newest = Some(idx);
+            }
+        }
+
+        if let Some(idx) = newest {
+            if available[idx].1.pkgver().vercmp(&package.pkgver()) != Ordering::Greater {
+                continue;
+            }
+
+            if ignored.contains(&package.name) {
+                println!(
+                    "warning: {}: ignoring package upgrade ({} -> {})",
+                    package.name,
+                    package.full_version(),
+                    available[idx].1.pkgver()
+                );
+                continue;
+            }
+
+            upgrades.push(idx);
+        }
+    }
+
+    Ok(upgrades)
+}
+
+fn print_summary(installed: &[InstalledPackage], upgrades: &[(&Repository, &RepoPackage)]) {
+    let mut rows = vec![[
+        String::from("Package"),
+        String::from("Old Version"),
+        String::from("New Version"),
+        String::from("Repository"),
+        String::from("Size"),
+    ]];
+
+    for (repo, package) in upgrades.iter() {
+        let old = match installed.iter().find(|i| i.name == package.name) {
+            Some(old) => old.full_version(),
+            None => String::from("-"),
+        };
+
+        rows.push([
+            package.name.clone(),
+            old,
+            package.pkgver().to_string(),
+            repo.name.clone(),
+            format_size(package.size),
+        ]);
+    }
+
+    let mut widths = [0; 5];
+    for row in rows.iter() {
+        for (width, column) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(column.len());
+        }
+    }
+
+    for row in rows.iter() {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(column, width)| format!("{:<width$}", column, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+
+    let total: u64 = upgrades.iter().map(|(_, p)| p.size).sum();
+    println!();
+    println!("Total download size: {}", format_size(total));
+}
+
+pub fn format_size(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} {}", bytes, units[unit])
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+fn confirm(prompt: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    print!("{} [Y/n] ", prompt);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+
+    Ok(matches!(
+        answer.trim().to_lowercase().as_str(),
+        "" | "y" | "yes"
+    ))
+}
+
+/// Downloads the package archives into the cache, each from the first mirror
+/// that has a copy matching the repository's checksum, reusing what's already
+/// cached if it's intact.
+async fn fetch_packages(
+    client: &Client,
+    cache: &Path,
+    packages: &[(&Repository, &RepoPackage)],
+    parallel: usize,
+    retry: &Retry,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let mut archives = Vec::new();
+    let mut downloads = Vec::new();
+
+    for (repo, package) in packages.iter() {
+        let dest = cache.join(&package.filename);
+        archives.push(dest.clone());
+
+        if downloader::file_sha256sum_matches(dest.to_str().unwrap(), &package.sha256sum) {
+            continue;
+        }
+
+        downloads.push(Download {
+            urls: repo
+                .mirrors
+                .iter()
+                .map(|mirror| format!("{}/{}", mirror.trim_end_matches('/'), package.filename))
+                .collect(),
+            dest,
+            sha256sum: Some(package.sha256sum.clone()),
+        });
+    }
+
+    downloader::download_all(client, &downloads, parallel, retry).await?;
+
+    Ok(archives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::time::Duration;
+
+    use crate::test_server::{Response, TestServer};
+
+    fn repo(name: &str) -> Repository {
+        Repository {
+            name: String::from(name),
+            mirrors: Vec::new(),
+        }
+    }
+
+    fn repo_package(name: &str, version: &str, replaces: &[&str]) -> RepoPackage {
+        RepoPackage {
+            filename: format!("{}-{}-1-any.pkg.tar.gz", name, version),
+            name: String::from(name),
+            base: String::from(name),
+            epoch: None,
+            version: String::from(version),
+            release: 1,
+            description: None,
+            url: None,
+            arch: String::from("any"),
+            builddate: 0,
+            size: 0,
+            installed_size: 0,
+            sha256sum: String::new(),
+            license: Vec::new(),
+            depends: Vec::new(),
+            provides: Vec::new(),
+            conflicts: Vec::new(),
+            replaces: replaces.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_find_upgrades() {
+        let (core, extra) = (repo("core"), repo("extra"));
+        let core_packages = [
+            repo_package("foo", "1.1", &[]),
+            repo_package("bar", "1.0", &[]),
+            repo_package("newbaz", "1.0", &["baz"]),
+            repo_package("qux", "1.0", &[]),
+        ];
+        let extra_packages = [
+            repo_package("foo", "1.2", &[]),
+            repo_package("baz", "2.0", &[]),
+            repo_package("qux", "1.0", &[]),
+            repo_package("unrelated", "1.0", &[]),
+        ];
+        // grouped by repository in the order that they're configured, the
+        // same as when they're loaded from the sync databases
+        let available: Vec<(&Repository, &RepoPackage)> = core_packages
+            .iter()
+            .map(|p| (&core, p))
+            .chain(extra_packages.iter().map(|p| (&extra, p)))
+            .collect();
+
+        let installed = vec![
+            InstalledPackage::for_test("foo", "1.0"),
+            InstalledPackage::for_test("bar", "1.1"),
+            InstalledPackage::for_test("baz", "1.0"),
+            InstalledPackage::for_test("qux", "0.9"),
+        ];
+
+        // foo takes the newest version wherever it is, bar is already newer
+        // than the repo, baz gets replaced rather than upgraded and qux comes
+        // from core since it has the same version as extra
+        let upgrades = find_upgrades(&installed, &available, &[]).unwrap();
+        assert_eq!(upgrades, vec![4, 2, 3]);
+        assert_eq!(available[4].0.name, "extra");
+        assert_eq!(available[3].0.name, "core");
+
+        // ignoring a package holds back both upgrades and replacements
+        let ignored = vec![String::from("foo"), String::from("baz")];
+        assert_eq!(
+            find_upgrades(&installed, &available, &ignored).unwrap(),
+            vec![3]
+        );
+    }
+
+    #[test]
+    fn test_find_upgrades_replacement_installed() {
+        let core = repo("core");
+        let packages = [
+            repo_package("baz", "2.0", &[]),
+            repo_package("newbaz", "1.0", &["baz<2"]),
+        ];
+        let available: Vec<(&Repository, &RepoPackage)> =
+            packages.iter().map(|p| (&core, p)).collect();
+
+        // only versions of baz before 2 are replaced and an installed
+        // replacement never triggers another replacement
+        let installed = vec![InstalledPackage::for_test("baz", "2.0")];
+        assert!(find_upgrades(&installed, &available, &[])
+            .unwrap()
+            .is_empty());
+
+        let installed = vec![
+            InstalledPackage::for_test("baz", "1.0"),
+            InstalledPackage::for_test("newbaz", "1.0"),
+        ];
+        assert_eq!(find_upgrades(&installed, &available, &[]).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_install_reason() {
+        let targets = depends::parse_all(&[String::from("foo"), String::from("newbaz")]).unwrap();
+        let installed = vec![
+            InstalledPackage::for_test("foo", "1.0"),
+            InstalledPackage::for_test("bar", "1.0"),
+        ];
+
+        let reason =
+            |name: &str| install_reason(&repo_package(name, "2.0", &[]), &targets, &installed);
+        assert_eq!(reason("foo"), None);
+        assert_eq!(reason("newbaz"), None);
+        assert_eq!(reason("bar"), None);
+        assert_eq!(reason("libfoo"), Some(InstallReason::Dependency));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_packages() {
+        let cache = std::env::temp_dir().join(format!("mpm-upgrade-fetch-{}", std::process::id()));
+        if cache.exists() {
+            fs::remove_dir_all(&cache).unwrap();
+        }
+        fs::create_dir_all(&cache).unwrap();
+
+        let corrupt = TestServer::start(|_| Response::new(200, b"corrupt"));
+        let working = TestServer::start(|_| Response::new(200, b"package"));
+
+        let mut package = repo_package("foo", "1.0", &[]);
+        fs::write(cache.join("expected"), "package").unwrap();
+        package.sha256sum =
+            downloader::file_sha256sum(cache.join("expected").to_str().unwrap()).unwrap();
+
+        let mut core = repo("core");
+        core.mirrors = vec![corrupt.url.clone(), working.url.clone()];
+
+        let client = Client::builder().no_proxy().build().unwrap();
+        let retry = Retry {
+            retries: 0,
+            delay: Duration::ZERO,
+        };
+        // already cached so nothing is downloaded for this one
+        let mut bar = repo_package("bar", "2.0", &[]);
+        bar.sha256sum = package.sha256sum.clone();
+        fs::write(cache.join("bar-2.0-1-any.pkg.tar.gz"), "package").unwrap();
+        let mut extra = repo("extra");
+        extra.mirrors = vec![working.url.clone()];
+
+        let paths = fetch_packages(
+            &client,
+            &cache,
+            &[(&core, &package), (&extra, &bar)],
+            2,
+            &retry,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            paths,
+            vec![
+                cache.join("foo-1.0-1-any.pkg.tar.gz"),
+                cache.join("bar-2.0-1-any.pkg.tar.gz")
+            ]
+        );
+        let path = &paths[0];
+        assert_eq!(fs::read(path).unwrap(), b"package");
+        assert_eq!(corrupt.requests()[0].path, "/foo-1.0-1-any.pkg.tar.gz");
+
+        // a good copy in the cache doesn't need to be downloaded again
+        fetch_packages(&client, &cache, &[(&core, &package)], 2, &retry)
+            .await
+            .unwrap();
+        assert_eq!(working.requests().len(), 1);
+
+        core.mirrors = vec![corrupt.url.clone()];
+        fs::remove_file(path).unwrap();
+        let err = fetch_packages(&client, &cache, &[(&core, &package)], 2, &retry)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("checksum mismatch"));
+        assert!(!path.exists());
+        assert!(!cache.join("foo-1.0-1-any.pkg.tar.gz.part").exists());
+
+        fs::remove_dir_all(&cache).unwrap();
+    }
 }
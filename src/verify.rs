@@ -0,0 +1,358 @@
+use std::fs;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use clap::ArgMatches;
+
+use super::db::{self, LocalDatabase};
+use super::downloader;
+use super::package::mtree::{EntryType, MtreeEntry};
+
+/// Something about an installed file that doesn't match its package's
+/// manifest.
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    Missing,
+    WrongType,
+    Mode {
+        expected: u32,
+        actual: u32,
+    },
+    /// The uid and gid of the file.
+    Owner {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    Checksum,
+    /// The file couldn't be read to check it, e.g., because we're not root.
+    Unreadable(String),
+    Link {
+        expected: String,
+        actual: String,
+    },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Missing => write!(f, "missing"),
+            Problem::WrongType => write!(f, "file type changed"),
+            Problem::Mode { expected, actual } => {
+                write!(f, "mode changed ({:o} -> {:o})", expected, actual)
+            }
+            Problem::Owner { expected, actual } => write!(
+                f,
+                "owner changed ({}:{} -> {}:{})",
+                expected.0, expected.1, actual.0, actual.1
+            ),
+            Problem::Checksum => write!(f, "checksum changed"),
+            Problem::Unreadable(err) => write!(f, "unable to read: {}", err),
+            Problem::Link { expected, actual } => {
+                write!(f, "symlink points to {} instead of {}", actual, expected)
+            }
+        }
+    }
+}
+
+pub fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let (root, dbpath) = db::paths(cli);
+    let localdb = LocalDatabase::open(&dbpath)?;
+    let installed = localdb.packages()?;
+
+    let packages = match cli.values_of("package") {
+        Some(names) => {
+            let mut packages = Vec::new();
+            for name in names {
+                match installed.iter().find(|p| p.name == name) {
+                    Some(package) => packages.push(package),
+                    None => return Err(format!("{} is not installed", name).into()),
+                }
+            }
+            packages
+        }
+        None => installed.iter().collect(),
+    };
+
+    // every file from every package goes into one list so that a single big
+    // package doesn't leave most of the threads with nothing to do
+    let mut checks = Vec::new();
+    for package in packages.iter() {
+        let mtree = match localdb.mtree(&package.name)? {
+            Some(mtree) => mtree,
+            None => {
+                eprintln!("warning: no manifest for {}, skipping", package.name);
+                continue;
+            }
+        };
+
+        for entry in mtree.entries.into_iter() {
+            // admins are expected to change these
+            let backup = package.backup.contains_key(&entry.path);
+            checks.push((package.name.as_str(), entry, backup));
+        }
+    }
+
+    let problems = check_all(&root, &checks);
+    for (name, entry, problem) in problems.iter() {
+        println!(
+            "{}: {}: {}",
+            name,
+            root.join(&entry.path).display(),
+            problem
+        );
+    }
+
+    println!(
+        "{} packages, {} files checked, {} problems found",
+        packages.len(),
+        checks.len(),
+        problems.len()
+    );
+
+    if !problems.is_empty() {
+        return Err(format!("found {} problems", problems.len()).into());
+    }
+
+    Ok(())
+}
+
+/// Checks the entries across as many threads as we have CPUs, returning the
+/// problems in the same order as the entries they were found in.
+fn check_all<'a>(
+    root: &Path,
+    checks: &'a [(&'a str, MtreeEntry, bool)],
+) -> Vec<(&'a str, &'a MtreeEntry, Problem)> {
+    let threads = thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1)
+        .min(checks.len().max(1));
+    let next = AtomicUsize::new(0);
+
+    let mut found: Vec<(usize, Problem)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut found = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        if i >= checks.len() {
+                            break;
+                        }
+
+                        let (_, entry, backup) = &checks[i];
+                        if let Some(problem) = check_entry(root, entry, *backup) {
+                            found.push((i, problem));
+                        }
+                    }
+                    found
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect()
+    });
+
+    found.sort_by_key(|(i, _)| *i);
+    found
+        .into_iter()
+        .map(|(i, problem)| (checks[i].0, &checks[i].1, problem))
+        .collect()
+}
+
+/// Compares the installed file against its manifest entry, only reporting
+/// the first problem since a missing file or one of the wrong type makes
+/// everything else meaningless.
+fn check_entry(root: &Path, entry: &MtreeEntry, backup: bool) -> Option<Problem> {
+    let path = root.join(&entry.path);
+    let meta = match fs::symlink_metadata(&path) {
+        Ok(meta) => meta,
+        Err(_) => return Some(Problem::Missing),
+    };
+
+    let file_type = meta.file_type();
+    let matches = match entry.kind {
+        EntryType::File => file_type.is_file(),
+        EntryType::Dir => file_type.is_dir(),
+        EntryType::Link => file_type.is_symlink(),
+    };
+    if !matches {
+        return Some(Problem::WrongType);
+    }
+
+    let owner = (meta.uid(), meta.gid());
+    if owner != (entry.uid, entry.gid) {
+        return Some(Problem::Owner {
+            expected: (entry.uid, entry.gid),
+            actual: owner,
+        });
+    }
+
+    if entry.kind == EntryType::Link {
+        let expected = entry.link.clone().unwrap_or_default();
+        let actual = match fs::read_link(&path) {
+            Ok(actual) => actual.to_str().unwrap_or_default().to_string(),
+            Err(_) => return Some(Problem::Missing),
+        };
+
+        if actual != expected {
+            return Some(Problem::Link { expected, actual });
+        }
+
+        return None;
+    }
+
+    let mode = meta.permissions().mode() & 0o7777;
+    if mode != entry.mode {
+        return Some(Problem::Mode {
+            expected: entry.mode,
+            actual: mode,
+        });
+    }
+
+    if let (EntryType::File, Some(sha256), false) = (&entry.kind, &entry.sha256, backup) {
+        match downloader::file_sha256sum(path.to_str().unwrap()) {
+            Ok(sum) if &sum == sha256 => (),
+            Ok(_) => return Some(Problem::Checksum),
+            Err(err) => return Some(Problem::Unreadable(err.to_string())),
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs as unix_fs;
+    use std::path::PathBuf;
+
+    use crate::package::mtree::Mtree;
+
+    fn test_root(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("mpm-verify-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_check_all() {
+        let root = test_root("check");
+        fs::create_dir_all(root.join("usr/bin")).unwrap();
+        fs::create_dir_all(root.join("etc")).unwrap();
+        for file in [
+            "usr/bin/ok",
+            "usr/bin/changed",
+            "usr/bin/mode",
+            "usr/bin/owner",
+            "etc/foo.conf",
+        ] {
+            fs::write(root.join(file), file).unwrap();
+            fs::set_permissions(root.join(file), fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        unix_fs::symlink("ok", root.join("usr/bin/link")).unwrap();
+        unix_fs::symlink("changed", root.join("usr/bin/badlink")).unwrap();
+
+        let mtree = Mtree::from_dir(&root).unwrap();
+        let meta = fs::metadata(root.join("usr/bin/owner")).unwrap();
+        let (uid, gid) = (meta.uid(), meta.gid());
+
+        fs::write(root.join("usr/bin/changed"), "something else").unwrap();
+        fs::write(root.join("etc/foo.conf"), "edited").unwrap();
+        fs::set_permissions(root.join("usr/bin/mode"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::remove_file(root.join("usr/bin/badlink")).unwrap();
+        unix_fs::symlink("elsewhere", root.join("usr/bin/badlink")).unwrap();
+        fs::remove_file(root.join("usr/bin/ok")).unwrap();
+        fs::create_dir(root.join("usr/bin/ok")).unwrap();
+
+        let mut checks: Vec<(&str, MtreeEntry, bool)> = mtree
+            .entries
+            .into_iter()
+            .map(|mut e| {
+                if e.path == "usr/bin/owner" {
+                    e.uid += 1;
+                }
+                let backup = e.path == "etc/foo.conf";
+                ("foo", e, backup)
+            })
+            .collect();
+        checks.push((
+            "bar",
+            MtreeEntry {
+                path: String::from("usr/bin/missing"),
+                kind: EntryType::File,
+                mode: 0o755,
+                uid: 0,
+                gid: 0,
+                size: None,
+                link: None,
+                sha256: None,
+            },
+            false,
+        ));
+
+        let problems: Vec<(&str, &str, String)> = check_all(&root, &checks)
+            .into_iter()
+            .map(|(name, entry, problem)| (name, entry.path.as_str(), problem.to_string()))
+            .collect();
+
+        assert_eq!(
+            problems,
+            vec![
+                (
+                    "foo",
+                    "usr/bin/badlink",
+                    String::from("symlink points to elsewhere instead of changed")
+                ),
+                ("foo", "usr/bin/changed", String::from("checksum changed")),
+                (
+                    "foo",
+                    "usr/bin/mode",
+                    String::from("mode changed (755 -> 644)")
+                ),
+                ("foo", "usr/bin/ok", String::from("file type changed")),
+                (
+                    "foo",
+                    "usr/bin/owner",
+                    format!("owner changed ({}:{} -> {}:{})", uid + 1, gid, uid, gid)
+                ),
+                ("bar", "usr/bin/missing", String::from("missing")),
+            ]
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_check_unreadable() {
+        let root = test_root("unreadable");
+        fs::write(root.join("secret"), "secret").unwrap();
+        let mut entry = Mtree::from_dir(&root).unwrap().entries.remove(0);
+        entry.mode = 0;
+        fs::set_permissions(root.join("secret"), fs::Permissions::from_mode(0o000)).unwrap();
+
+        // a file that we can't read isn't one that's been changed, but root
+        // can read it anyway
+        match fs::read(root.join("secret")) {
+            Ok(_) => assert_eq!(check_entry(&root, &entry, false), None),
+            Err(err) => assert_eq!(
+                check_entry(&root, &entry, false),
+                Some(Problem::Unreadable(err.to_string()))
+            ),
+        }
+        assert_eq!(
+            Problem::Unreadable(String::from("Permission denied (os error 13)")).to_string(),
+            "unable to read: Permission denied (os error 13)"
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+use std::cmp::Ordering;
+use std::str::FromStr;
+
+use clap::ArgMatches;
+
+/// A package version in the form `[epoch:]version[-release]`.
+///
+/// The `Ord` implementation is a total order suitable for sorting where a
+/// version without a release sorts before the same version with one. Use
+/// `vercmp` to compare versions the way that dependency constraints do, where
+/// the release is only considered if both sides have one.
+#[derive(Clone, Debug, Eq)]
+pub struct Version {
+    pub epoch: u32,
+    pub version: String,
+    pub release: Option<String>,
+}
+
+pub fn run(cli: &ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
+    let a: Version = cli.value_of("a").unwrap().parse()?;
+    let b: Version = cli.value_of("b").unwrap().parse()?;
+
+    println!(
+        "{}",
+        match a.vercmp(&b) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        }
+    );
+
+    Ok(())
+}
+
+impl Version {
+    pub fn new(epoch: Option<u32>, version: &str, release: u32) -> Self {
+        Version {
+            epoch: epoch.unwrap_or(0),
+            version: version.to_string(),
+            release: Some(release.to_string()),
+        }
+    }
+
+    /// Compares two versions following pacman's rules: epoch first, then the
+    /// version and then the release, but only if both versions have one.
+    pub fn vercmp(&self, other: &Version) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| rpmvercmp(&self.version, &other.version))
+            .then_with(|| match (&self.release, &other.release) {
+                (Some(a), Some(b)) => rpmvercmp(a, b),
+                _ => Ordering::Equal,
+            })
+    }
+}
+
+impl FromStr for Version {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (epoch, rest) = match s.split_once(':') {
+            Some((epoch, rest)) => match epoch.parse() {
+                Ok(epoch) => (epoch, rest),
+                Err(_) => return Err(format!("invalid epoch in version {}", s))?,
+            },
+            None => (0, s),
+        };
+
+        let (version, release) = match rest.rsplit_once('-') {
+            Some((version, release)) if !release.is_empty() => (version, Some(release.to_string())),
+            Some(_) => return Err(format!("empty release in version {}", s))?,
+            None => (rest, None),
+        };
+
+        if version.is_empty() {
+            return Err(format!("empty version in {}", s).into());
+        }
+
+        if s.contains(|c: char| c.is_whitespace() || c == '<' || c == '>' || c == '=') {
+            return Err(format!("invalid character in version {}", s).into());
+        }
+
+        Ok(Version {
+            epoch,
+            version: version.to_string(),
+            release,
+        })
+    }
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.epoch > 0 {
+            write!(f, "{}:", self.epoch)?;
+        }
+
+        write!(f, "{}", self.version)?;
+
+        if let Some(release) = &self.release {
+            write!(f, "-{}", release)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.epoch
+            .cmp(&other.epoch)
+            .then_with(|| rpmvercmp(&self.version, &other.version))
+            .then_with(|| match (&self.release, &other.release) {
+                (Some(a), Some(b)) => rpmvercmp(a, b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+/// Compares two version strings segment by segment the same way that rpm and
+/// pacman do: runs of digits compare numerically, runs of letters compare
+/// lexically, a numeric segment is always newer than an alphabetic one and
+/// separators only matter when their lengths differ.
+pub fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let a = a.as_bytes();
+    let b = b.as_bytes();
+    let (mut one, mut two) = (0, 0);
+
+    while one < a.len() && two < b.len() {
+        let (start1, start2) = (one, two);
+
+        while one < a.len() && !a[one].is_ascii_alphanumeric() {
+            one += 1;
+        }
+        while two < b.len() && !b[two].is_ascii_alphanumeric() {
+            two += 1;
+        }
+
+        if one >= a.len() || two >= b.len() {
+            break;
+        }
+
+        // if the separator lengths were different, we are also finished
+        if one - start1 != two - start2 {
+            return (one - start1).cmp(&(two - start2));
+        }
+
+        let isnum = a[one].is_ascii_digit();
+        let segment = |s: &[u8], mut i: usize| {
+            let start = i;
+            while i < s.len()
+                && if isnum {
+                    s[i].is_ascii_digit()
+                } else {
+                    s[i].is_ascii_alphabetic()
+                }
+            {
+                i += 1;
+            }
+            (start, i)
+        };
+
+        let (seg1_start, seg1_end) = segment(a, one);
+        let (seg2_start, seg2_end) = segment(b, two);
+
+        // the segments are different types: one numeric, the other alpha
+        // (i.e., empty); numeric segments are always newer than alpha ones
+        if seg2_start == seg2_end {
+            return if isnum {
+                Ordering::Greater
+            } else {
+                Ordering::Less
+            };
+        }
+
+        let mut seg1 = &a[seg1_start..seg1_end];
+        let mut seg2 = &b[seg2_start..seg2_end];
+
+        if isnum {
+            while seg1.len() > 1 && seg1[0] == b'0' {
+                seg1 = &seg1[1..];
+            }
+            while seg2.len() > 1 && seg2[0] == b'0' {
+                seg2 = &seg2[1..];
+            }
+
+            // whichever number has more digits wins
+            match seg1.len().cmp(&seg2.len()) {
+                Ordering::Equal => (),
+                ordering => return ordering,
+            }
+        }
+
+        match seg1.cmp(seg2) {
+            Ordering::Equal => (),
+            ordering => return ordering,
+        }
+
+        one = seg1_end;
+        two = seg2_end;
+    }
+
+    // all of the segments compared identically but the separators were
+    // different
+    if one >= a.len() && two >= b.len() {
+        return Ordering::Equal;
+    }
+
+    // the final showdown: we never want a remaining alpha string to beat an
+    // empty string
+    if (one >= a.len() && !b[two].is_ascii_alphabetic())
+        || (one < a.len() && a[one].is_ascii_alphabetic())
+    {
+        Ordering::Less
+    } else {
+        Ordering::Greater
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vercmp(a: &str, b: &str) -> i32 {
+        let a: Version = a.parse().unwrap();
+        let b: Version = b.parse().unwrap();
+
+        match a.vercmp(&b) {
+            Ordering::Less => -1,
+            Ordering::Equal => 0,
+            Ordering::Greater => 1,
+        }
+    }
+
+    /// A tiny linear congruential generator so that the property tests are
+    /// deterministic and don't need any extra dependencies.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self, max: u64) -> u64 {
+            self.0 = self
+                .0
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (self.0 >> 33) % max
+        }
+
+        fn version(&mut self) -> String {
+            let alphabet = ["0", "1", "2", "10", "01", "a", "b", "rc", "alpha"];
+            let separators = [".", "_", "+", "..", "~"];
+            let mut version = String::new();
+
+            if self.next(4) == 0 {
+                version += &format!("{}:", self.next(3));
+            }
+
+            for i in 0..(1 + self.next(4)) {
+                if i > 0 {
+                    version += separators[self.next(separators.len() as u64) as usize];
+                }
+                version += alphabet[self.next(alphabet.len() as u64) as usize];
+            }
+
+            version += &format!("-{}", 1 + self.next(3));
+            version
+        }
+    }
+
+    fn sample_versions() -> Vec<Version> {
+        let mut lcg = Lcg(42);
+        (0..150)
+            .map(|_| lcg.version())
+            .filter_map(|v| v.parse().ok())
+            .collect()
+    }
+
+    #[test]
+    fn test_basic() {
+        assert_eq!(vercmp("1.5.0", "1.5.0"), 0);
+        assert_eq!(vercmp("1.5.1", "1.5.0"), 1);
+        assert_eq!(vercmp("1.5.1", "1.5"), 1);
+        assert_eq!(vercmp("1.5.0", "1.5"), 1);
+        assert_eq!(vercmp("1.5.1", "1.5.2"), -1);
+        assert_eq!(vercmp("1.0", "1.0.0"), -1);
+        assert_eq!(vercmp("1.01", "1.1"), 0);
+        assert_eq!(vercmp("1.001", "1.1"), 0);
+        assert_eq!(vercmp("10", "9"), 1);
+    }
+
+    #[test]
+    fn test_alpha() {
+        assert_eq!(vercmp("1.5b", "1.5"), -1);
+        assert_eq!(vercmp("1.5b", "1.5.1"), -1);
+        assert_eq!(vercmp("1.0a", "1.0alpha"), -1);
+        assert_eq!(vercmp("1.0alpha", "1.0b"), -1);
+        assert_eq!(vercmp("1.0b", "1.0beta"), -1);
+        assert_eq!(vercmp("1.0beta", "1.0rc"), -1);
+        assert_eq!(vercmp("1.0rc", "1.0"), -1);
+        assert_eq!(vercmp("1.5.a", "1.5"), 1);
+        assert_eq!(vercmp("1.5.b", "1.5.a"), 1);
+        assert_eq!(vercmp("1.5.1", "1.5.b"), 1);
+    }
+
+    #[test]
+    fn test_separators() {
+        assert_eq!(vercmp("1.0", "1_0"), 0);
+        assert_eq!(vercmp("1.0", "1+0"), 0);
+        assert_eq!(vercmp("1..0", "1.0"), 1);
+        assert_eq!(vercmp("1.0.", "1.0"), 1);
+    }
+
+    #[test]
+    fn test_epoch_and_release() {
+        assert_eq!(vercmp("0:1.0", "1.0"), 0);
+        assert_eq!(vercmp("1:1.0", "2.0"), 1);
+        assert_eq!(vercmp("1:1.0", "1:2.0"), -1);
+        assert_eq!(vercmp("1.0-1", "1.0-2"), -1);
+        assert_eq!(vercmp("1.0-2", "1.0-10"), -1);
+        assert_eq!(vercmp("1.1-1", "1.0-2"), 1);
+        assert_eq!(vercmp("1.0", "1.0-1"), 0);
+        assert_eq!(vercmp("1.0-1", "1.0"), 0);
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        let version: Version = "2:1.0.3-4".parse().unwrap();
+        assert_eq!(version.epoch, 2);
+        assert_eq!(version.version, "1.0.3");
+        assert_eq!(version.release.as_deref(), Some("4"));
+        assert_eq!(version.to_string(), "2:1.0.3-4");
+        assert_eq!("0:1.0".parse::<Version>().unwrap().to_string(), "1.0");
+        assert_eq!(Version::new(None, "1.0", 3).to_string(), "1.0-3");
+
+        assert!("a:1.0".parse::<Version>().is_err());
+        assert!("1:-1".parse::<Version>().is_err());
+        assert!("1.0-".parse::<Version>().is_err());
+        assert!(">1.0".parse::<Version>().is_err());
+        assert!("1.0 -1".parse::<Version>().is_err());
+    }
+
+    #[test]
+    fn test_ordering_without_release() {
+        let bare: Version = "1.0".parse().unwrap();
+        let released: Version = "1.0-1".parse().unwrap();
+        assert_eq!(bare.vercmp(&released), Ordering::Equal);
+        assert!(bare < released);
+    }
+
+    #[test]
+    fn test_property_reflexive() {
+        for a in sample_versions().iter() {
+            assert_eq!(a.cmp(a), Ordering::Equal, "{}", a);
+            assert_eq!(rpmvercmp(&a.version, &a.version.clone()), Ordering::Equal);
+        }
+    }
+
+    #[test]
+    fn test_property_antisymmetric() {
+        let versions = sample_versions();
+        for a in versions.iter() {
+            for b in versions.iter() {
+                assert_eq!(a.cmp(b), b.cmp(a).reverse(), "{} <=> {}", a, b);
+                assert_eq!(a.vercmp(b), b.vercmp(a).reverse(), "{} <=> {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_property_transitive() {
+        let versions = sample_versions();
+        for a in versions.iter().take(60) {
+            for b in versions.iter().take(60) {
+                for c in versions.iter().take(60) {
+                    if a <= b && b <= c {
+                        assert!(a <= c, "{} <= {} <= {}", a, b, c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_property_sort_is_consistent() {
+        let mut versions = sample_versions();
+        versions.sort();
+        for pair in versions.windows(2) {
+            assert_ne!(pair[0].cmp(&pair[1]), Ordering::Greater);
+        }
+    }
+
+    #[test]
+    fn test_property_epoch_dominates() {
+        for a in sample_versions().iter() {
+            let mut bumped = a.clone();
+            bumped.epoch += 1;
+            bumped.version = String::from("0");
+            assert_eq!(bumped.vercmp(a), Ordering::Greater, "{} > {}", bumped, a);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::Once;
+
+fn test_dir(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("mpm-root-{}-{}", name, std::process::id()));
+    if path.exists() {
+        fs::remove_dir_all(&path).unwrap();
+    }
+    fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// Builds a package archive by hand, with each file containing its own path.
+fn build_package(dir: &Path, name: &str, depends: &[&str], files: &[&str]) -> PathBuf {
+    let pkgdir = dir.join(format!("{}-pkg", name));
+    let mut mtree = String::from("#mtree\n");
+    for file in files.iter() {
+        let path = pkgdir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, file).unwrap();
+        mtree += &format!("./{} type=file mode=644 uid=0 gid=0\n", file);
+    }
+
+    let mut pkginfo = format!("pkgname = {}\npkgver = 1.0-1\narch = any\n", name);
+    for depend in depends.iter() {
+        pkginfo += &format!("depend = {}\n", depend);
+    }
+    fs::write(pkgdir.join(".PKGINFO"), pkginfo).unwrap();
+    fs::write(pkgdir.join(".MTREE"), mtree).unwrap();
+
+    let archive = dir.join(format!("{}-1.0-1-any.pkg.tar.gz", name));
+    let status = Command::new("bsdtar")
+        .arg("czf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&pkgdir)
+        .args([".PKGINFO", ".MTREE", "usr"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    archive
+}
+
+/// Runs mpm with an empty config so that nothing from the host's
+/// /etc/mpm.conf, like its cache directory, is used.
+fn mpm(args: &[&str]) -> Output {
+    static WRITE_CONFIG: Once = Once::new();
+    let config = std::env::temp_dir().join(format!("mpm-root-{}.conf", std::process::id()));
+    WRITE_CONFIG.call_once(|| fs::write(&config, "repos: []\n").unwrap());
+
+    Command::new(env!("CARGO_BIN_EXE_mpm"))
+        .env("MPM_CONFIG", &config)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_install_remove_root() {
+    let dir = test_dir("install");
+    let root = dir.join("root");
+    let foo = build_package(&dir, "foo", &[], &["usr/bin/foo"]);
+    let bar = build_package(
+        &dir,
+        "bar",
+        &["foo"],
+        &["usr/bin/bar", "usr/share/bar/data"],
+    );
+
+    let output = mpm(&[
+        "install",
+        "--root",
+        root.to_str().unwrap(),
+        bar.to_str().unwrap(),
+        foo.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "installing foo 1.0-1\ninstalling bar 1.0-1\n"
+    );
+    assert_eq!(fs::read(root.join("usr/bin/foo")).unwrap(), b"usr/bin/foo");
+    assert!(root.join("usr/share/bar/data").exists());
+    assert!(root.join("var/lib/mpm/local/foo/desc").exists());
+    assert!(!root.join("var/lib/mpm/db.lck").exists());
+
+    let output = mpm(&["remove", "--root", root.to_str().unwrap(), "foo"]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr),
+        "error: unable to remove packages:\n  foo is required by bar\n"
+    );
+    assert!(root.join("usr/bin/foo").exists());
+
+    let output = mpm(&["remove", "--root", root.to_str().unwrap(), "foo", "bar"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!root.join("usr/bin/foo").exists());
+    assert!(!root.join("usr/share/bar").exists());
+    assert!(!root.join("var/lib/mpm/local/foo").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_install_dbpath() {
+    let dir = test_dir("dbpath");
+    let root = dir.join("root");
+    let dbpath = dir.join("db");
+    let foo = build_package(&dir, "foo", &[], &["usr/bin/foo"]);
+
+    let output = mpm(&[
+        "install",
+        "--root",
+        root.to_str().unwrap(),
+        "--dbpath",
+        dbpath.to_str().unwrap(),
+        foo.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(root.join("usr/bin/foo").exists());
+    assert!(dbpath.join("local/foo/desc").exists());
+    assert!(!root.join("var").exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_query() {
+    let dir = test_dir("query");
+    let root = dir.join("root");
+    let root_arg = root.to_str().unwrap();
+    let foo = build_package(&dir, "foo", &[], &["usr/bin/foo"]);
+    let bar = build_package(&dir, "bar", &["foo"], &["usr/bin/bar"]);
+
+    let output = mpm(&[
+        "install",
+        "--root",
+        root_arg,
+        "--asdeps",
+        foo.to_str().unwrap(),
+    ]);
+    assert!(output.status.success(), "{:?}", output);
+    let output = mpm(&["install", "--root", root_arg, bar.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let output = mpm(&["query", "--root", root_arg]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "bar 1.0-1\nfoo 1.0-1\n"
+    );
+
+    let output = mpm(&["query", "--root", root_arg, "--json", "--files", "bar"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "[{{\"name\":\"bar\",\"files\":[\"{0}/usr/\",\"{0}/usr/bin/\",\"{0}/usr/bin/bar\"]}}]\n",
+            root.display()
+        )
+    );
+
+    let output = mpm(&["query", "--root", root_arg, "--info", "foo"]);
+    let info = String::from_utf8_lossy(&output.stdout);
+    assert!(info.contains("Required By    : bar\n"), "{}", info);
+    assert!(info.contains("Install Reason : Installed as a dependency"));
+    let output = mpm(&["query", "--root", root_arg, "--info", "--json", "foo"]);
+    let info = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        info.starts_with("[{\"name\":\"foo\",\"version\":\"1.0-1\""),
+        "{}",
+        info
+    );
+    assert!(info.contains("\"required_by\":[\"bar\"]"), "{}", info);
+    assert!(info.contains("\"reason\":\"dependency\""), "{}", info);
+
+    let file = root.join("usr/bin/foo");
+    let output = mpm(&[
+        "query",
+        "--root",
+        root_arg,
+        "--owns",
+        file.to_str().unwrap(),
+    ]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{} is owned by foo 1.0-1\n", file.display())
+    );
+    // paths and the root can both be relative to where we're run from
+    let output = Command::new(env!("CARGO_BIN_EXE_mpm"))
+        .current_dir(&dir)
+        .args(["query", "--root", "./root", "-o", "./root/usr/bin/foo"])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).ends_with("is owned by foo 1.0-1\n"));
+
+    let missing = root.join("usr/bin/missing");
+    let output = mpm(&["query", "--root", root_arg, "-o", missing.to_str().unwrap()]);
+    assert!(!output.status.success());
+
+    // foo only becomes an orphan once nothing needs it
+    let output = mpm(&["query", "--root", root_arg, "--orphans"]);
+    assert!(output.stdout.is_empty());
+    let output = mpm(&["remove", "--root", root_arg, "bar"]);
+    assert!(output.status.success(), "{:?}", output);
+    let output = mpm(&["query", "--root", root_arg, "--orphans", "--json"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "[{\"name\":\"foo\",\"version\":\"1.0-1\"}]\n"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_verify() {
+    let dir = test_dir("verify");
+    let root = dir.join("root");
+    let root_arg = root.to_str().unwrap();
+    let foo = build_package(&dir, "foo", &[], &["usr/bin/foo", "usr/share/foo/data"]);
+
+    // looking at a root that nothing's installed into leaves it alone
+    let output = mpm(&["verify", "--root", root_arg]);
+    assert!(output.status.success(), "{:?}", output);
+    let output = mpm(&["query", "--root", root_arg]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!root.exists());
+
+    let output = mpm(&["install", "--root", root_arg, foo.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let output = mpm(&["verify", "--root", root_arg]);
+    assert!(output.status.success(), "{:?}", output);
+
+    fs::remove_file(root.join("usr/share/foo/data")).unwrap();
+    let output = mpm(&["verify", "--root", root_arg, "foo"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains(&format!(
+        "foo: {}: missing\n",
+        root.join("usr/share/foo/data").display()
+    )));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_config() {
+    let dir = test_dir("config");
+    let empty = dir.join("empty.conf");
+    let invalid = dir.join("invalid.conf");
+    fs::write(&empty, "repos: []\n").unwrap();
+    fs::write(&invalid, "repositories: []\n").unwrap();
+
+    let sync = |config: Option<&Path>, env: &Path| {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_mpm"));
+        command
+            .env("MPM_CONFIG", env)
+            .args(["sync", "--dbpath", dir.join("db").to_str().unwrap()]);
+        if let Some(config) = config {
+            command.arg("--config").arg(config);
+        }
+        String::from_utf8_lossy(&command.output().unwrap().stderr).to_string()
+    };
+
+    // the environment is used unless there's a flag
+    assert!(sync(None, &invalid).contains(&format!("invalid config {}", invalid.display())));
+    assert!(sync(Some(&empty), &invalid).contains("no repositories are configured"));
+    assert!(sync(None, &dir.join("missing.conf")).contains("unable to read"));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_clean() {
+    let root = test_dir("clean");
+    let root_arg = root.to_str().unwrap();
+    let foo = build_package(&root, "foo", &[], &["usr/bin/foo"]);
+
+    let output = mpm(&["install", "--root", root_arg, foo.to_str().unwrap()]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let cache = root.join("var/cache/mpm/pkg");
+    fs::create_dir_all(&cache).unwrap();
+    fs::copy(&foo, cache.join("foo-1.0-1-any.pkg.tar.gz")).unwrap();
+    fs::write(cache.join("foo-0.9-1-any.pkg.tar.gz"), "old").unwrap();
+    fs::write(cache.join("bar-1.0-1-any.pkg.tar.gz.part"), "partial").unwrap();
+
+    // nothing is removed while an upgrade is downloading into the cache
+    fs::write(cache.join("cache.lck"), "1").unwrap();
+    let output = mpm(&["clean", "--root", root_arg]);
+    assert!(!output.status.success());
+    assert!(cache.join("bar-1.0-1-any.pkg.tar.gz.part").exists());
+    fs::remove_file(cache.join("cache.lck")).unwrap();
+
+    let output = mpm(&["clean", "--root", root_arg]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("2 files removed"));
+    assert!(cache.join("foo-1.0-1-any.pkg.tar.gz").exists());
+    assert!(!cache.join("foo-0.9-1-any.pkg.tar.gz").exists());
+    assert!(!cache.join("bar-1.0-1-any.pkg.tar.gz.part").exists());
+
+    let output = mpm(&["clean", "--root", root_arg, "--keep", "0"]);
+    assert!(output.status.success(), "{:?}", output);
+    assert!(!cache.join("foo-1.0-1-any.pkg.tar.gz").exists());
+
+    let output = mpm(&["clean", "--root", root_arg, "--keep", "all"]);
+    assert!(!output.status.success());
+
+    fs::remove_dir_all(&root).unwrap();
+}